@@ -1,6 +1,11 @@
 use std::env;
 use std::error::Error;
-use std::path::Path;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use object::{Object, ObjectSection, ObjectSymbol, RelocationKind, SectionKind, SymbolKind};
+
+use blue_layout as layout;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let cargo_raw = env::var("CARGO")?;
@@ -17,10 +22,13 @@ fn main() -> Result<(), Box<dyn Error>> {
         .expect("llvm-objcopy not found");
 
     // rerun if libs changed
-    println!("cargo:rerun-if-changed={}", manifest_dir.join("real").display());
+    println!(
+        "cargo:rerun-if-changed={}",
+        manifest_dir.join("real").display()
+    );
 
     // careful: stage1 is size sensitive, so always release
-    build(
+    let stage1 = build(
         &cargo,
         &objcopy,
         &manifest_dir.join("loader-stage1"),
@@ -31,7 +39,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     )?;
 
     // rust debug symbols are *too big* for 16-bit code
-    build(
+    let stage2 = build(
         &cargo,
         &objcopy,
         &manifest_dir.join("loader-stage2"),
@@ -41,7 +49,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         "blue-loader-stage2",
     )?;
 
-    build(
+    let stage3 = build(
         &cargo,
         &objcopy,
         &manifest_dir.join("loader-stage3"),
@@ -51,7 +59,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         "blue-loader-stage3",
     )?;
 
-    Ok(())
+    check_layout(&stage1, &stage2, &stage3)
 }
 
 fn build(
@@ -62,7 +70,7 @@ fn build(
     triple: &str,
     release: bool,
     outputname: &str,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<PathBuf, Box<dyn Error>> {
     println!("cargo:rerun-if-changed={}", source.display());
 
     let name = source
@@ -72,10 +80,16 @@ fn build(
         .ok_or("path is not valid utf-8")?;
     let target_dir = root_target_dir.join(name);
 
+    // frame sizes and the relocations behind every call, for
+    // check_stack. neither changes the code that ends up in the binary.
+    let rustflags = ["-Zemit-stack-sizes", "-Clink-arg=--emit-relocs"];
+
     let mut cmd = std::process::Command::new(cargo);
     cmd.current_dir(source)
         .arg("build")
-        .arg(format!("--target-dir={}", target_dir.display()));
+        .arg(format!("--target-dir={}", target_dir.display()))
+        .env("CARGO_ENCODED_RUSTFLAGS", rustflags.join("\x1f"))
+        .env_remove("RUSTFLAGS");
     if release {
         cmd.arg("--release");
     }
@@ -92,7 +106,11 @@ fn build(
     let binary = outputdir.join(outputname.to_string() + ".bin");
 
     let mut objcopy_cmd = std::process::Command::new(objcopy);
-    objcopy_cmd.arg("-O").arg("binary").arg(output).arg(&binary);
+    objcopy_cmd
+        .arg("-O")
+        .arg("binary")
+        .arg(&output)
+        .arg(&binary);
     let objcopy_status = objcopy_cmd.status()?;
     if !objcopy_status.success() {
         Err("objcopy failed")?;
@@ -101,5 +119,446 @@ fn build(
     let varname = format!("BLUE_{}", name.replace("-", "_").to_uppercase());
     println!("cargo:rustc-env={}={}", varname, binary.display());
 
+    Ok(output)
+}
+
+// a loaded section of a stage, as linked
+struct Section {
+    name: String,
+    start: u64,
+    end: u64,
+}
+
+// a range of memory, [start, end)
+#[derive(Clone, Copy)]
+struct Region {
+    name: &'static str,
+    start: u64,
+    end: u64,
+}
+
+impl Region {
+    fn contains(&self, s: &Section) -> bool {
+        self.start <= s.start && s.end <= self.end
+    }
+
+    fn overlaps(&self, s: &Section) -> bool {
+        s.start < self.end && self.start < s.end
+    }
+}
+
+// the sections that take up memory, from a stage's ELF
+fn read_elf(path: &Path) -> Result<Vec<Section>, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    let elf = object::File::parse(&*data)?;
+
+    let mut sections = Vec::new();
+    for section in elf.sections().filter(is_alloc) {
+        if section.size() > 0 {
+            sections.push(Section {
+                name: section.name()?.to_owned(),
+                start: section.address(),
+                end: section.address() + section.size(),
+            });
+        }
+    }
+
+    Ok(sections)
+}
+
+// summarize where each section of a stage went into report, and add
+// every section that strays outside its region or into a reserved one
+// to problems
+fn check_stage(
+    stage: &str,
+    sections: &[Section],
+    region_for: impl Fn(&Section) -> Region,
+    reserved: &[Region],
+    report: &mut String,
+    problems: &mut Vec<String>,
+) {
+    writeln!(report, "{}:", stage).unwrap();
+    for section in sections {
+        let region = region_for(section);
+        writeln!(
+            report,
+            "  {:<12} {:#07x}..{:#07x} {:>6} bytes, {} free in {}",
+            section.name,
+            section.start,
+            section.end,
+            section.end - section.start,
+            region.end.saturating_sub(section.end),
+            region.name,
+        )
+        .unwrap();
+
+        if !region.contains(section) {
+            problems.push(format!(
+                "{} {} at {:#x}..{:#x} does not fit in {} at {:#x}..{:#x}",
+                stage,
+                section.name,
+                section.start,
+                section.end,
+                region.name,
+                region.start,
+                region.end
+            ));
+        }
+        for r in reserved.iter().filter(|r| r.overlaps(section)) {
+            problems.push(format!(
+                "{} {} at {:#x}..{:#x} overlaps {} at {:#x}..{:#x}",
+                stage, section.name, section.start, section.end, r.name, r.start, r.end
+            ));
+        }
+    }
+}
+
+// room left on the stack for BIOS calls, and the real mode code around
+// them, which rustc knows nothing about. SeaBIOS switches to its own
+// stack for disk calls, but other BIOSes run on ours; a few hundred
+// bytes is what they are usually documented to need.
+const BIOS_STACK: u64 = 0x200;
+
+// a function in a stage, as linked
+struct Function {
+    name: String,
+    start: u64,
+    end: u64,
+    // what its prologue takes off the stack, from .stack_sizes
+    frame: u64,
+    // every function it calls, or at least refers to
+    calls: Vec<usize>,
+}
+
+// every function in a stage, and which of them might be called
+// indirectly, say from a vtable, because their address is in data
+struct CallGraph {
+    functions: Vec<Function>,
+    indirect: Vec<usize>,
+    // the size of a return address
+    word: u64,
+}
+
+fn read_le(data: &[u8]) -> u64 {
+    data.iter().rev().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn is_alloc(section: &object::Section) -> bool {
+    match section.flags() {
+        object::SectionFlags::Elf { sh_flags } => sh_flags & object::elf::SHF_ALLOC as u64 != 0,
+        _ => false,
+    }
+}
+
+// the call graph of a stage, with frame sizes filled in
+fn read_call_graph(path: &Path) -> Result<CallGraph, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    let elf = object::File::parse(&*data)?;
+    let word = if elf.is_64() { 8 } else { 4 };
+
+    let mut functions = Vec::new();
+    for symbol in elf.symbols() {
+        if symbol.kind() == SymbolKind::Text && symbol.size() > 0 {
+            functions.push(Function {
+                name: symbol.name()?.to_owned(),
+                start: symbol.address(),
+                end: symbol.address() + symbol.size(),
+                frame: 0,
+                calls: Vec::new(),
+            });
+        }
+    }
+    functions.sort_by_key(|f| f.start);
+    functions.dedup_by_key(|f| f.start);
+    let containing = |functions: &[Function], address: u64| {
+        let after = functions.partition_point(|f| f.start <= address);
+        after.checked_sub(1).filter(|&i| address < functions[i].end)
+    };
+
+    // a function address, then its frame size in ULEB128, over and over
+    let sizes = elf
+        .section_by_name(".stack_sizes")
+        .ok_or("no .stack_sizes section, was it built with -Z emit-stack-sizes?")?
+        .data()?;
+    let mut pos = 0;
+    while pos + word <= sizes.len() {
+        let address = read_le(&sizes[pos..pos + word]);
+        pos += word;
+        let mut frame = 0;
+        let mut shift = 0;
+        loop {
+            let b = *sizes.get(pos).ok_or("truncated .stack_sizes section")?;
+            pos += 1;
+            frame |= ((b & 0x7f) as u64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+        if let Ok(i) = functions.binary_search_by_key(&address, |f| f.start) {
+            functions[i].frame = frame;
+        }
+    }
+
+    // --emit-relocs keeps every reference the linker filled in. rustc
+    // puts each function in its own section, so every call between
+    // functions is one of these.
+    let mut indirect = Vec::new();
+    for section in elf.sections().filter(is_alloc) {
+        let code = section.kind() == SectionKind::Text;
+        let contents = section.data()?;
+        for (address, relocation) in section.relocations() {
+            let size = relocation.size() as usize / 8;
+            let at = (address - section.address()) as usize;
+            let value = match contents.get(at..at + size) {
+                Some(field) => read_le(field),
+                None => continue,
+            };
+            // the field holds the address itself, or for calls and
+            // jumps, the distance to it from the end of the field
+            let target = match relocation.kind() {
+                RelocationKind::Absolute => value,
+                RelocationKind::Relative | RelocationKind::PltRelative => {
+                    let shift = 64 - 8 * size as u32;
+                    let distance = ((value << shift) as i64 >> shift) as u64;
+                    address.wrapping_add(size as u64).wrapping_add(distance)
+                }
+                _ => continue,
+            };
+
+            let callee = match containing(&functions, target) {
+                Some(callee) => callee,
+                None => continue,
+            };
+            if !code {
+                indirect.push(callee);
+            } else if let Some(caller) = containing(&functions, address) {
+                if caller != callee {
+                    functions[caller].calls.push(callee);
+                }
+            }
+        }
+    }
+    indirect.sort_unstable();
+    indirect.dedup();
+
+    Ok(CallGraph {
+        functions,
+        indirect,
+        word: word as u64,
+    })
+}
+
+// the most stack a call to function f can take, counting each function
+// once per call chain so that recursion ends. deepest_from[f] also keeps
+// the callee that gets there.
+fn deepest(
+    graph: &CallGraph,
+    f: usize,
+    deepest_from: &mut [Option<(u64, Option<usize>)>],
+    active: &mut [bool],
+) -> u64 {
+    if let Some((bytes, _)) = deepest_from[f] {
+        return bytes;
+    }
+    if active[f] {
+        return 0;
+    }
+
+    active[f] = true;
+    let mut worst = (0, None);
+    for &callee in graph.functions[f].calls.iter() {
+        let bytes = deepest(graph, callee, deepest_from, active);
+        if bytes > worst.0 {
+            worst = (bytes, Some(callee));
+        }
+    }
+    active[f] = false;
+
+    // the call itself pushes a return address
+    let bytes = graph.word + graph.functions[f].frame + worst.0;
+    deepest_from[f] = Some((bytes, worst.1));
+    bytes
+}
+
+// check the most stack a stage can take from _start, plus extra for
+// what rustc can't see, against STACK_SIZE
+//
+// this goes by the frame sizes rustc recorded and the calls the linker
+// filled in. it can't follow function pointers, so an indirect call is
+// assumed to reach the deepest function whose address is in data, once.
+// recursion is only counted once round, and asm frames not at all.
+fn check_stack(
+    stage: &str,
+    path: &Path,
+    extra: u64,
+    report: &mut String,
+    problems: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let graph = read_call_graph(path)?;
+    let functions = &graph.functions;
+    let entry = functions
+        .iter()
+        .position(|f| f.name == "_start")
+        .ok_or("no _start symbol")?;
+
+    let mut deepest_from = vec![None; functions.len()];
+    let mut active = vec![false; functions.len()];
+    let mut bytes = deepest(&graph, entry, &mut deepest_from, &mut active);
+    let mut chain = Vec::new();
+    let mut next = Some(entry);
+    while let Some(f) = next.filter(|_| chain.len() < functions.len()) {
+        chain.push(functions[f].name.as_str());
+        next = deepest_from[f].and_then(|(_, callee)| callee);
+    }
+
+    let indirect_worst = graph
+        .indirect
+        .iter()
+        .map(|&f| (deepest(&graph, f, &mut deepest_from, &mut active), f))
+        .max();
+    if let Some((indirect_bytes, f)) = indirect_worst {
+        bytes += indirect_bytes;
+        chain.push("(indirectly)");
+        chain.push(functions[f].name.as_str());
+    }
+    bytes += extra;
+
+    let budget = layout::STACK_SIZE as u64;
+    writeln!(
+        report,
+        "  {:<12} {:>6} bytes at most, {} free in STACK_SIZE",
+        "stack",
+        bytes,
+        budget.saturating_sub(bytes)
+    )
+    .unwrap();
+    for name in chain.iter() {
+        writeln!(report, "    {}", name).unwrap();
+    }
+
+    if bytes > budget {
+        problems.push(format!(
+            "{} may use {} bytes of stack, more than STACK_SIZE {:#x}",
+            stage, bytes, budget
+        ));
+    }
     Ok(())
 }
+
+// check every stage against the memory it has to fit in, failing with
+// a report of all the problems at once
+fn check_layout(stage1: &Path, stage2: &Path, stage3: &Path) -> Result<(), Box<dyn Error>> {
+    let mut report = String::new();
+    let mut problems = Vec::new();
+
+    // stage1's addresses are offsets in the boot sector
+    let sections = read_elf(stage1)?;
+    let body = Region {
+        name: "the stage1 body",
+        start: 0,
+        end: layout::STAGE1_SECTOR_SIZE as u64,
+    };
+    let boot_code = Region {
+        name: "the boot code area",
+        start: 0,
+        end: layout::STAGE1_END as u64,
+    };
+    check_stage(
+        "stage1",
+        &sections,
+        |s| match s.name.as_str() {
+            ".sectorsize" | ".blocklist" => boot_code,
+            _ => body,
+        },
+        &[],
+        &mut report,
+        &mut problems,
+    );
+    check_stack("stage1", stage1, BIOS_STACK, &mut report, &mut problems)?;
+
+    // stage2's addresses are offsets in BOOT_SEGMENT, the same as the
+    // stack, GDT and page tables
+    let stack_bottom = (layout::STACK_TOP - layout::STACK_SIZE) as u64;
+    let tables_end = (layout::P4 + layout::PAGE_TABLE_SIZE) as u64;
+    let sections = read_elf(stage2)?;
+    let stage2_region = Region {
+        name: "stage2's memory below the stack",
+        start: layout::STAGE2_ENTRY as u64,
+        end: stack_bottom,
+    };
+    let stage2_reserved = [
+        Region {
+            name: "the stack",
+            start: stack_bottom,
+            end: layout::STACK_TOP as u64,
+        },
+        Region {
+            name: "the GDT",
+            start: layout::GDT as u64,
+            end: layout::P1 as u64,
+        },
+        Region {
+            name: "the page tables",
+            start: layout::P1 as u64,
+            end: tables_end,
+        },
+    ];
+    check_stage(
+        "stage2",
+        &sections,
+        |_| stage2_region,
+        &stage2_reserved,
+        &mut report,
+        &mut problems,
+    );
+    check_stack("stage2", stage2, BIOS_STACK, &mut report, &mut problems)?;
+
+    // stage3 is linked at physical addresses, and still uses the GDT
+    // and page tables stage2 left behind
+    let segment_base = (layout::BOOT_SEGMENT as u64) << 4;
+    let sections = read_elf(stage3)?;
+    let realmode = Region {
+        name: "real mode memory below the stack",
+        start: layout::REALMODE_BASE as u64,
+        end: stack_bottom,
+    };
+    let stage3_region = Region {
+        name: "memory below the EBDA",
+        start: layout::STAGE3_ENTRY as u64,
+        end: layout::EBDA_LOW as u64,
+    };
+    let stage3_reserved = [Region {
+        name: "stage2's GDT and page tables",
+        start: segment_base + layout::GDT as u64,
+        end: segment_base + tables_end,
+    }];
+    check_stage(
+        "stage3",
+        &sections,
+        |s| {
+            if s.name == ".realmode" {
+                realmode
+            } else {
+                stage3_region
+            }
+        },
+        &stage3_reserved,
+        &mut report,
+        &mut problems,
+    );
+    // BIOS calls from stage3 switch to a real mode stack of their own
+    check_stack("stage3", stage3, 0, &mut report, &mut problems)?;
+
+    if problems.is_empty() {
+        // shown with cargo build -vv
+        print!("{}", report);
+        return Ok(());
+    }
+
+    eprint!("{}", report);
+    for problem in problems.iter() {
+        eprintln!("error: {}", problem);
+    }
+    Err(format!("{} loader memory layout problem(s)", problems.len()).into())
+}
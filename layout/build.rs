@@ -0,0 +1,70 @@
+use std::env;
+use std::error::Error;
+use std::fmt::Write;
+use std::path::Path;
+
+// turn the assignments in layout.ld into Rust constants
+//
+// this is no linker script parser: it only understands NAME = number;
+// with comments, which is all layout.ld should ever hold.
+fn main() -> Result<(), Box<dyn Error>> {
+    let manifest_dir_raw = env::var("CARGO_MANIFEST_DIR")?;
+    let source = Path::new(&manifest_dir_raw).join("../layout.ld");
+    println!("cargo:rerun-if-changed={}", source.display());
+
+    let text = std::fs::read_to_string(&source)?;
+    let mut out = String::new();
+    let mut doc: Vec<String> = Vec::new();
+    let mut in_comment = false;
+    for (number, line) in text.lines().enumerate() {
+        let mut line = line.trim();
+
+        // block comments above an assignment become its documentation
+        if in_comment || line.starts_with("/*") {
+            let body = line.trim_start_matches("/*");
+            let (body, end) = match body.find("*/") {
+                Some(i) => (&body[..i], true),
+                None => (body, false),
+            };
+            let body = body.trim().trim_start_matches('*').trim();
+            if !body.is_empty() {
+                doc.push(body.to_owned());
+            }
+            in_comment = !end;
+            continue;
+        }
+        if line.is_empty() {
+            doc.clear();
+            continue;
+        }
+
+        // and so does a comment after one, on the same line
+        if let Some(i) = line.find("/*") {
+            let comment = line[i + 2..].trim_end_matches("*/").trim();
+            doc.push(comment.to_owned());
+            line = line[..i].trim();
+        }
+
+        let bad = || format!("layout.ld line {}: expected NAME = number;", number + 1);
+        let (name, value) = line
+            .strip_suffix(';')
+            .and_then(|l| l.split_once('='))
+            .ok_or_else(bad)?;
+        let name = name.trim();
+        let value = value.trim();
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| bad())?;
+
+        for line in doc.drain(..) {
+            writeln!(out, "/// {}", line)?;
+        }
+        writeln!(out, "pub const {}: u32 = {:#x};", name, value)?;
+    }
+
+    let dest = Path::new(&env::var("OUT_DIR")?).join("layout.rs");
+    std::fs::write(dest, out)?;
+    Ok(())
+}
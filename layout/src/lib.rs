@@ -0,0 +1,21 @@
+//! The boot memory layout, as constants generated from `layout.ld` so
+//! that the linker scripts, the loader stages, and blue-tool all agree.
+
+#![no_std]
+
+include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+
+/// how many (sector, count) entries fit in stage1's blocklist
+pub const STAGE1_BLOCKLIST_ENTRIES: usize = ((STAGE1_END - STAGE1_BLOCKLIST) / 8) as usize;
+
+/// the part of the MBR that is boot code, before the disk signature
+pub const BOOT_CODE_SIZE: usize = STAGE1_END as usize;
+
+/// marks the sector holding the boot code stage1 replaced
+pub const BACKUP_MAGIC: &[u8; 8] = b"BLUEMBR1";
+
+/// the sector holding the boot code stage1 replaced, past the
+/// protective MBR, the GPT header, and BACKUP_SECTOR_OFFSET bytes
+pub const fn backup_lba(sector_size: u16) -> u64 {
+    2 + BACKUP_SECTOR_OFFSET as u64 / sector_size as u64
+}
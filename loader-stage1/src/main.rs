@@ -3,8 +3,14 @@
 #![feature(asm_const)]
 #![feature(asm_sym)]
 
-const SECTOR_SIZE: u16 = 512;
-const BOOT_SEGMENT: u16 = 0x07c0;
+use blue_layout::{
+    BOOT_SEGMENT, STACK_TOP, STAGE1_BLOCKLIST_ENTRIES, STAGE2_ENTRY as STAGE2_OFFSET,
+};
+
+// filled in by blue-tool, along with the blocklist
+#[link_section = ".sectorsize"]
+#[no_mangle]
+static SECTOR_SIZE: u16 = 512;
 
 #[repr(C, packed)]
 struct Blocks {
@@ -14,10 +20,10 @@ struct Blocks {
 
 #[link_section = ".blocklist"]
 #[no_mangle]
-static STAGE2: [Blocks; 10] = [
+static STAGE2: [Blocks; STAGE1_BLOCKLIST_ENTRIES] = [
     Blocks {
         offset: 0x1,
-        count: 0xb000 / SECTOR_SIZE as u32,
+        count: 0xb000 / 512,
     },
     Blocks {
         offset: 0,
@@ -80,6 +86,8 @@ pub fn inform(s: &[u8]) {
     for &c in s {
         unsafe {
             core::arch::asm!(
+                // copy to the debug console, for emulators that have one
+                "out 0xe9, al",
                 "int 0x10",
                 in("ax") (0x0e00 | (c as u16)),
                 in("ebx") 7,
@@ -88,10 +96,26 @@ pub fn inform(s: &[u8]) {
     }
 }
 
-#[inline]
-fn error(msg: &[u8]) {
+// so that no sector of stage2 straddles a 64K boundary
+const _: () = assert!(((BOOT_SEGMENT << 4) + STAGE2_OFFSET) % 4096 == 0);
+
+// how many times to try each read, resetting the disk in between
+const READ_TRIES: u8 = 3;
+
+// print the message and the BIOS status code in hex, then give up
+// called from a few places, so kept out of line to save space
+#[inline(never)]
+fn error(msg: &[u8], status: u8) -> ! {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
     inform(b"ERROR: ");
     inform(msg);
+    inform(&[
+        b' ',
+        b'0',
+        b'x',
+        HEX[(status >> 4) as usize],
+        HEX[(status & 0xf) as usize],
+    ]);
     loop {
         hlt();
     }
@@ -117,8 +141,10 @@ impl Dap {
             startlba,
         }
     }
+    // both of these return the BIOS status, 0 for success
+
     #[inline]
-    unsafe fn reset(drv: u8) -> bool {
+    unsafe fn reset(drv: u8) -> u8 {
         let mut ret: u8;
         core::arch::asm!(
             "int $0x13",
@@ -126,11 +152,11 @@ impl Dap {
             in("ah") 0u8,
             in("dl") drv,
         );
-        ret == 0
+        ret
     }
 
     #[inline]
-    unsafe fn read(&self, drv: u8) -> bool {
+    unsafe fn read(&self, drv: u8) -> u8 {
         let mut ret: u8;
         let address = self as *const Self;
         core::arch::asm!(
@@ -143,13 +169,83 @@ impl Dap {
             in("ah") 0x42u8,
             in("dl") drv,
         );
-        ret == 0
+        ret
     }
 }
 
+// sectors per track and heads, for BIOSes without LBA extensions
+type Geometry = (u32, u32);
+
+// None if the BIOS can do LBA reads (AH=42h) on the drive, otherwise
+// the geometry to do CHS reads (AH=02h) with
+#[inline]
+unsafe fn geometry(drv: u8) -> Option<Geometry> {
+    let carry: u8;
+    let bx: u16;
+    let cx: u16;
+    core::arch::asm!(
+        "int $0x13",
+        "setc {}",
+        out(reg_byte) carry,
+        inout("bx") 0x55aau16 => bx,
+        lateout("cx") cx,
+        inout("ax") 0x4100u16 => _,
+        inout("dx") drv as u16 => _,
+    );
+    // bit 0 of cx: the disk address packet calls are there
+    if carry == 0 && bx == 0xaa55 && cx & 1 != 0 {
+        return None;
+    }
+
+    let ax: u16;
+    let cx: u16;
+    let dx: u16;
+    core::arch::asm!(
+        // floppies get a pointer to their parameters in es:di
+        "push es",
+        "int $0x13",
+        "pop es",
+        inout("ax") 0x0800u16 => ax,
+        lateout("bx") _,
+        lateout("cx") cx,
+        inout("dx") drv as u16 => dx,
+        lateout("di") _,
+    );
+    if ax >> 8 != 0 {
+        error(b"geometry", (ax >> 8) as u8);
+    }
+    Some(((cx & 0x3f) as u32, (dx >> 8) as u32 + 1))
+}
+
+// read count sectors at lba into addr with AH=02h, returning the BIOS
+// status. the count must not run past the end of the track.
+#[inline]
+unsafe fn read_chs(drv: u8, lba: u32, count: u32, addr: u32, geometry: Geometry) -> u8 {
+    let (sectors, heads) = geometry;
+    let track = lba / sectors;
+    let head = track % heads;
+    let cylinder = track / heads;
+    let ret: u16;
+    core::arch::asm!(
+        "push es",
+        "mov es, {:x}",
+        "int $0x13",
+        "pop es",
+        in(reg) (addr >> 4) as u16,
+        in("bx") (addr & 0xf) as u16,
+        // cylinder in ch, and its top two bits above the sector in cl
+        in("cx") ((cylinder << 8) | ((cylinder >> 2) & 0xc0) | (lba % sectors + 1)) as u16,
+        in("dx") ((head << 8) | drv as u32) as u16,
+        inout("ax") 0x0200 | count as u16 => ret,
+    );
+    (ret >> 8) as u8
+}
+
 #[link_section = ".startup"]
 #[no_mangle]
 extern "C" fn _start() -> ! {
+    // the BIOS leaves the boot drive in dl
+    let drive: u8;
     unsafe {
         core::arch::asm!(
             "jmpl ${0}, $2f",
@@ -159,10 +255,12 @@ extern "C" fn _start() -> ! {
             "movw %ax, %ds",
             "movw %ax, %es",
             "movw %ax, %ss",
-            "movl $0x0FFF0, %esp",
-            "movl $0x0FFF0, %ebp",
+            "movl ${1}, %esp",
+            "movl ${1}, %ebp",
             "sti",
             const BOOT_SEGMENT,
+            const STACK_TOP,
+            out("dl") drive,
             options(att_syntax),
         );
     }
@@ -170,41 +268,78 @@ extern "C" fn _start() -> ! {
     inform(b"BLUEloader/1\r\n");
 
     unsafe {
-        let drive = 0x80; // always the boot drive
-
-        let mut dest = (STAGE2_ENTRY as *const unsafe fn() -> !) as u32;
+        // where the next sector goes, as a physical address
+        let mut addr = (BOOT_SEGMENT << 4) + STAGE2_OFFSET;
+        // volatile, so the default is not folded in at compile time
+        let sector_size = core::ptr::read_volatile(&SECTOR_SIZE) as u32;
+        let mut chs = None;
 
-        if !Dap::reset(drive) {
-            error(b"reset");
-        }
-
-        for chunk in STAGE2.iter() {
+        for (i, chunk) in STAGE2.iter().enumerate() {
             if chunk.count == 0 {
                 break;
             }
 
-            // FIXME bioses will only really move 16 sectors at a time...
-            let d = Dap::new(
-                chunk.count as u16,
-                dest | ((BOOT_SEGMENT as u32) << 16),
-                chunk.offset as u64,
-            );
+            // booted from CD, the blocklist is empty and there may be
+            // no disk to reset at all
+            if i == 0 {
+                let status = Dap::reset(drive);
+                if status != 0 {
+                    error(b"reset", status);
+                }
+                chs = geometry(drive);
+            }
+
+            // BIOSes only promise 127 sectors per read, and some can't
+            // DMA across a 64K boundary, so split reads to suit. stage2
+            // starts 4096 aligned, so there is always room for one.
+            let mut lba = chunk.offset;
+            let end = chunk.offset + chunk.count;
+            while lba < end {
+                let room = (0x10000 - (addr & 0xffff)) / sector_size;
+                let mut count = (end - lba).min(127).min(room);
+                if let Some((sectors, _)) = chs {
+                    // CHS reads stop at the end of the track
+                    count = count.min(sectors - lba % sectors);
+                }
+
+                // normalized, so the offset can't wrap during the read
+                let d = Dap::new(count as u16, ((addr >> 4) << 16) | (addr & 0xf), lba as u64);
+                // floppies and USB emulation often need a few goes
+                let mut tries = 1;
+                loop {
+                    let status = match chs {
+                        None => d.read(drive),
+                        Some(geometry) => read_chs(drive, lba, count, addr, geometry),
+                    };
+                    if status == 0 {
+                        break;
+                    }
+                    if tries == READ_TRIES {
+                        error(b"read", status);
+                    }
+                    tries += 1;
+                    Dap::reset(drive);
+                }
 
-            if !d.read(drive) {
-                error(b"read");
-            } else {
-                dest += chunk.count as u32 * SECTOR_SIZE as u32;
+                addr += count * sector_size;
+                lba += count;
             }
         }
     }
 
-    // reset stack and jump to stage2
+    // reset stack and jump to stage2, with the boot drive as its
+    // argument and a return address it never uses
     unsafe {
         core::arch::asm!(
-            "mov esp, 0xfff0",
-            "mov ebp, 0xfff0",
+            "mov esp, {}",
+            "mov ebp, {}",
+            "push edx",
+            "push edx",
             "jmp {}",
+            const STACK_TOP,
+            const STACK_TOP,
             sym STAGE2_ENTRY,
+            in("edx") drive as u32,
             options(noreturn),
         );
     }
@@ -0,0 +1,80 @@
+use core::convert::TryInto;
+
+use blue_real::mbr::BOOT_CODE_SIZE;
+use blue_real::println;
+
+const TIMEOUT: u32 = 2 * blue_real::keyboard::TICKS_PER_SECOND;
+
+// if a key was pressed while the loader was starting, and blue-tool
+// kept the boot code it replaced, offer to boot that instead
+//
+// returns straight away unless a key is already waiting, so normal boots
+// never pause here. otherwise returns if there is no saved boot code, or
+// no key is pressed in time.
+pub fn offer(disk: &blue_real::disk::Disk, drive: u8) {
+    if blue_real::keyboard::key().is_none() {
+        return;
+    }
+
+    let boot_code = match blue_real::mbr::read_backup(disk) {
+        Ok(Some(boot_code)) if boot_code.iter().any(|&b| b != 0) => boot_code,
+        _ => return,
+    };
+
+    println!("press any key within 2 seconds to boot the original MBR");
+    if blue_real::keyboard::wait_for_key(TIMEOUT).is_none() {
+        return;
+    }
+
+    println!("booting the original MBR");
+    // the original boot code, with the partition table as it is now,
+    // straight out of the sector buffer
+    disk.read(0, |current| -> Result<(), &str> {
+        let rest = current[BOOT_CODE_SIZE..512].try_into().unwrap();
+        unsafe { chainload(&boot_code, rest, drive) }
+    })
+    .unwrap();
+}
+
+// copy the boot code and the rest of sector 0 after it to where the BIOS
+// loads boot sectors, and jump there the way the BIOS would, with the
+// boot drive in dl
+//
+// stage2 starts after stage1, so overwriting stage1 underneath it is fine.
+unsafe fn chainload(
+    boot_code: &[u8; BOOT_CODE_SIZE],
+    rest: &[u8; 512 - BOOT_CODE_SIZE],
+    drive: u8,
+) -> ! {
+    core::arch::asm!(
+        "cli",
+        "cld",
+        // si is reserved, so the sources come in bx and ax. nothing comes
+        // back here to need it restored.
+        "movw %bx, %si",
+        "movw %ax, %bx",
+        "movw ${segment}, %ax",
+        "movw %ax, %es",
+        "xorw %di, %di",
+        "movw ${code_size}, %cx",
+        "rep movsb",
+        "movw %bx, %si",
+        "movw ${rest_size}, %cx",
+        "rep movsb",
+        "xorw %ax, %ax",
+        "movw %ax, %ds",
+        "movw %ax, %es",
+        "movw %ax, %ss",
+        "movl ${base}, %esp",
+        "sti",
+        "ljmp $0, ${base}",
+        segment = const super::BOOT_SEGMENT,
+        base = const super::BOOT_SEGMENT << 4,
+        code_size = const BOOT_CODE_SIZE,
+        rest_size = const 512 - BOOT_CODE_SIZE,
+        in("bx") boot_code.as_ptr(),
+        in("ax") rest.as_ptr(),
+        in("dl") drive,
+        options(att_syntax, noreturn),
+    );
+}
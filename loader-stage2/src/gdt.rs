@@ -108,7 +108,7 @@ impl GlobalDescriptorTable {
         let ptr = DescriptorTablePointer {
             limit: (self.next * core::mem::size_of_val(&self.table[0]) - 1) as u16,
             // careful: our DS is set to BOOT_SEGMENT
-            base: self.table.as_ptr() as u32 + ((crate::BOOT_SEGMENT as u32) << 4),
+            base: self.table.as_ptr() as u32 + (crate::BOOT_SEGMENT << 4),
         };
         unsafe {
             ptr.lgdt();
@@ -202,7 +202,11 @@ pub unsafe fn unreal_mode() {
     );
 }
 
-pub unsafe fn long_mode(entry: unsafe extern "cdecl" fn() -> !) -> ! {
+// stage3 gets handoff's physical address as its argument
+pub unsafe fn long_mode(
+    entry: unsafe extern "cdecl" fn() -> !,
+    handoff: &'static blue_real::Handoff,
+) -> ! {
     let zero_idt = DescriptorTablePointer { limit: 0, base: 0 };
     let GDTInfo { data32, code64 } = load();
 
@@ -244,9 +248,10 @@ pub unsafe fn long_mode(entry: unsafe extern "cdecl" fn() -> !) -> ! {
         "push {3}",
         "retf",
         in(reg) *data32 as u32,
-        const ((crate::BOOT_SEGMENT as u32) << 4) + 0xfff0,
+        const (crate::BOOT_SEGMENT << 4) + blue_layout::STACK_TOP,
         in(reg) *code64 as u32,
         in(reg) entry as u32,
+        in("edi") handoff as *const _ as u32 + (crate::BOOT_SEGMENT << 4),
         options(noreturn),
     )
 }
@@ -7,14 +7,14 @@
 
 use fatfs::{Read, Seek, SeekFrom};
 
+use blue_layout::BOOT_SEGMENT;
 use blue_real::println;
 
 mod a20;
+mod chainload;
 mod gdt;
 mod paging;
 
-const BOOT_SEGMENT: u16 = 0x07c0;
-
 extern "cdecl" {
     static mut BSS: &'static mut [u8];
     fn STAGE3_ENTRY() -> !;
@@ -34,30 +34,16 @@ impl blue_real::Trampoline for RealTrampoline {
     }
 }
 
-#[link_section = ".startup"]
-#[no_mangle]
-extern "cdecl" fn _start() -> ! {
-    unsafe {
-        BSS.fill(0);
-    }
-
-    blue_real::set_trampoline(&RealTrampoline).unwrap();
-
-    println!("BLUEloader/2");
+// for stage3, passed by address in long_mode
+static mut HANDOFF: blue_real::Handoff = blue_real::Handoff { boot_drive: 0 };
 
-    unsafe {
-        a20::enable();
-        gdt::load();
-        gdt::unreal_mode();
-    }
-
-    let mut disk = blue_real::disk::Disk::open(0x80)
-        .unwrap()
-        .read_table()
-        .unwrap();
-    let fs = disk.open(0).unwrap();
-    let mut file = fs.root_dir().open_file("blue-loader-stage3.bin").unwrap();
+const STAGE3_NAME: &str = "blue-loader-stage3.bin";
 
+// read all of file into memory at STAGE3_ENTRY
+fn load_stage3<F: Read + Seek>(mut file: F)
+where
+    F::Error: core::fmt::Debug,
+{
     let size = file.seek(SeekFrom::End(0)).unwrap();
     file.seek(SeekFrom::Start(0)).unwrap();
 
@@ -72,13 +58,45 @@ extern "cdecl" fn _start() -> ! {
             out(reg) buf_address,
         );
     }
-    buf_address -= (BOOT_SEGMENT as u32) << 4;
+    buf_address -= BOOT_SEGMENT << 4;
 
     let buf = unsafe { core::slice::from_raw_parts_mut(buf_address as *mut u8, size as usize) };
     file.read_exact(buf).unwrap();
+}
+
+#[link_section = ".startup"]
+#[no_mangle]
+extern "cdecl" fn _start(boot_drive: u8) -> ! {
+    unsafe {
+        BSS.fill(0);
+    }
+
+    blue_real::set_trampoline(&RealTrampoline).unwrap();
+    blue_real::disk::set_boot_drive(boot_drive);
+
+    println!("BLUEloader/2");
+
+    unsafe {
+        a20::enable();
+        gdt::load();
+        gdt::unreal_mode();
+    }
+
+    // booted from CD, stage3 is in the ISO9660 filesystem instead
+    let disk = blue_real::disk::Disk::open_boot().unwrap();
+    if blue_real::disk::Disk::boot_is_cdrom() {
+        let fs = blue_real::iso9660::FileSystem::new(&disk).unwrap();
+        load_stage3(fs.root_dir().open_file(STAGE3_NAME).unwrap());
+    } else {
+        chainload::offer(&disk, boot_drive);
+        let mut disk = disk.read_table().unwrap();
+        let fs = disk.open(0).unwrap();
+        load_stage3(fs.root_dir().open_file(STAGE3_NAME).unwrap());
+    }
 
     unsafe {
+        HANDOFF.boot_drive = boot_drive;
         paging::load();
-        gdt::long_mode(STAGE3_ENTRY)
+        gdt::long_mode(STAGE3_ENTRY, &HANDOFF)
     }
 }
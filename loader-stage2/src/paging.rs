@@ -92,6 +92,6 @@ pub unsafe fn load() {
     core::arch::asm!(
         // move &P4 into cr3
         "mov cr3, {}",
-        in(reg) ((crate::BOOT_SEGMENT as u32) << 4) + core::ptr::addr_of!(P4) as u32,
+        in(reg) (crate::BOOT_SEGMENT << 4) + core::ptr::addr_of!(P4) as u32,
     );
 }
@@ -3,6 +3,8 @@
 #![feature(asm_sym)]
 #![feature(naked_functions)]
 
+use fatfs::Read;
+
 use blue_real::println;
 
 mod realmode;
@@ -15,7 +17,11 @@ extern "sysv64" {
 
 #[link_section = ".startup"]
 #[no_mangle]
-extern "cdecl" fn _start() -> ! {
+extern "cdecl" fn _start(handoff: u32) -> ! {
+    // stage2's copy may be overwritten from here on. only the low half
+    // of the register is set, so the address is a u32.
+    let handoff = unsafe { core::ptr::read(handoff as usize as *const blue_real::Handoff) };
+
     // move realmode section to true home, zero bss
     unsafe {
         REALMODE.copy_from_slice(REALMODE_IMAGE);
@@ -24,23 +30,33 @@ extern "cdecl" fn _start() -> ! {
 
     // install our real mode trampoline
     blue_real::set_trampoline(&realmode::LongModeTrampoline).unwrap();
+    blue_real::disk::set_boot_drive(handoff.boot_drive);
 
     println!("BLUEloader/3");
 
-    let mut disk = blue_real::disk::Disk::open(0x80)
-        .unwrap()
-        .read_table()
-        .unwrap();
-    let fs = disk.open(0).unwrap();
-    let mut file = fs.root_dir().open_file("hello.txt").unwrap();
-
     let mut buf = [0u8; 0x100];
-    use fatfs::Read;
-    let amt = file.read(&mut buf).unwrap();
+    let disk = blue_real::disk::Disk::open_boot().unwrap();
+    let amt = if blue_real::disk::Disk::boot_is_cdrom() {
+        let fs = blue_real::iso9660::FileSystem::new(&disk).unwrap();
+        let mut file = fs.root_dir().open_file("hello.txt").unwrap();
+        file.read(&mut buf).unwrap()
+    } else {
+        let mut disk = disk.read_table().unwrap();
+        let fs = disk.open(0).unwrap();
+        let mut file = fs.root_dir().open_file("hello.txt").unwrap();
+        file.read(&mut buf).unwrap()
+    };
     println!(
         "hello.txt: {:x?}",
         core::str::from_utf8(&buf[..amt]).unwrap()
     );
 
-    panic!("end of stage3");
+    // nothing left to load yet, so stop here. this is not a panic, so
+    // the boot tests can tell it apart from a real failure.
+    println!("end of stage3");
+    loop {
+        unsafe {
+            core::arch::asm!("hlt");
+        }
+    }
 }
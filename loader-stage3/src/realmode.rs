@@ -50,7 +50,7 @@ impl blue_real::Trampoline for LongModeTrampoline {
             "mov cr0, eax",
 
             // set up real-mode-friendly segments stack, and then set CS
-            "mov esp, 0xfff0",
+            "mov esp, {stack}",
             "xor ax, ax",
             "mov ds, ax",
             "mov es, ax",
@@ -95,6 +95,7 @@ impl blue_real::Trampoline for LongModeTrampoline {
             rsp = sym SAVE_RSP,
             rbp = sym SAVE_RBP,
             inner = sym SAVE_INNER,
+            stack = const blue_layout::STACK_TOP,
 
             out("rax") _,
             out("rcx") _,
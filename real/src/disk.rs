@@ -1,24 +1,69 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
 use crate::Result;
 
+// the drive the BIOS booted from, as handed down from stage1
+static BOOT_DRIVE: AtomicU8 = AtomicU8::new(0x80);
+
+// record the BIOS boot drive, for Disk::open_boot
+pub fn set_boot_drive(id: u8) {
+    BOOT_DRIVE.store(id, Ordering::SeqCst);
+}
+
+// the BIOS boot drive, or the first hard disk if it was never set
+pub fn boot_drive() -> u8 {
+    BOOT_DRIVE.load(Ordering::SeqCst)
+}
+
+// the one sector buffer every read goes through, so that no stack
+// frame has to hold a whole sector. the stack is only STACK_SIZE.
+struct Cache {
+    // the drive and absolute LBA of the sector in data, if any
+    holds: Option<(u8, u64)>,
+    data: [u8; crate::MAX_SECTOR_SIZE as usize],
+}
+
+static mut CACHE: Cache = Cache {
+    holds: None,
+    data: [0; crate::MAX_SECTOR_SIZE as usize],
+};
+
+// set while a read has CACHE lent out
+static CACHE_BUSY: AtomicBool = AtomicBool::new(false);
+
 #[derive(Clone, Debug)]
 pub struct Disk {
     id: u8,
     start: u64,
     length: u64,
+    sector_size: u16,
+    // None if the BIOS has LBA extensions, otherwise needed for CHS reads
+    geometry: Option<Geometry>,
+}
+
+// CHS geometry, as counts rather than the maximums the BIOS reports
+#[derive(Clone, Copy, Debug)]
+struct Geometry {
+    heads: u32,
+    sectors: u32,
 }
 
 #[derive(Debug)]
 pub struct DiskCursor {
     disk: Disk,
     pos: u64,
-    lba_in_buffer: Option<u64>,
-    buffer: [u8; crate::SECTOR_SIZE as usize],
 }
 
 #[derive(Clone, Debug)]
 pub struct PartitionedDisk {
     disk: Disk,
-    table: crate::mbr::PartitionTable,
+    table: PartitionTable,
+}
+
+#[derive(Clone, Debug)]
+pub enum PartitionTable {
+    Mbr(crate::mbr::PartitionTable),
+    Gpt(crate::gpt::PartitionTable),
 }
 
 #[repr(C, packed)]
@@ -32,18 +77,100 @@ struct Dap {
 }
 
 impl Disk {
+    // the disk we booted from
+    pub fn open_boot() -> Result<Self> {
+        Self::open(boot_drive())
+    }
+
     pub fn open(id: u8) -> Result<Self> {
         let mut s = Self {
             id,
             start: 0,
             length: 0,
+            sector_size: crate::MIN_SECTOR_SIZE,
+            geometry: None,
         };
         s.reset()?;
-        s.length = s.read_length()?;
+        let (length, sector_size) = if s.has_extensions() {
+            s.read_geometry()?
+        } else {
+            // old BIOSes and floppies: CHS only, and always 512 bytes
+            let (geometry, cylinders) = s.read_chs_geometry()?;
+            s.geometry = Some(geometry);
+            let length = cylinders as u64 * geometry.heads as u64 * geometry.sectors as u64;
+            (length, crate::MIN_SECTOR_SIZE)
+        };
+        s.length = length;
+        s.sector_size = sector_size;
         Ok(s)
     }
 
-    fn read_length(&mut self) -> Result<u64> {
+    // does the BIOS support LBA reads (AH=42h) on this drive?
+    fn has_extensions(&self) -> bool {
+        unsafe {
+            crate::real_asm!(
+                "push ebx",
+                "mov ah, 0x41",
+                "mov bx, 0x55aa",
+                "mov dl, [{0} + {id}]",
+                "int 0x13",
+                "jc 2f",
+                "cmp bx, 0xaa55",
+                "jne 2f",
+                // bit 0: the disk address packet calls are there
+                "and cl, 1",
+                "mov [{0} + {ret}], cl",
+                "2:",
+                "pop ebx",
+                id: u8 = alloc self.id,
+                ret: u8 = alloc 0,
+            );
+
+            *ret != 0
+        }
+    }
+
+    // returns (geometry, number of cylinders) from AH=08h
+    fn read_chs_geometry(&self) -> Result<(Geometry, u32)> {
+        unsafe {
+            crate::real_asm!(
+                // floppies get a pointer to their parameters in es:di
+                "push es",
+                "push di",
+                "push ebx",
+                "mov ah, 0x08",
+                "mov dl, [{0} + {id}]",
+                "xor di, di",
+                "int 0x13",
+                "mov [{0} + {ret}], ah",
+                "mov [{0} + {cx}], cx",
+                "mov [{0} + {dh}], dh",
+                "pop ebx",
+                "pop di",
+                "pop es",
+                id: u8 = alloc self.id,
+                ret: u8 = alloc 1,
+                cx: u16 = alloc 0,
+                dh: u8 = alloc 0,
+            );
+
+            if *ret != 0 {
+                return Err("could not read disk geometry");
+            }
+
+            // cl holds the sector count and the top of the cylinder
+            let sectors = (*cx & 0x3f) as u32;
+            let cylinders = ((*cx >> 8) | ((*cx & 0xc0) << 2)) as u32 + 1;
+            let heads = *dh as u32 + 1;
+            if sectors == 0 {
+                return Err("bad disk geometry");
+            }
+            Ok((Geometry { heads, sectors }, cylinders))
+        }
+    }
+
+    // returns (length in sectors, bytes per sector)
+    fn read_geometry(&mut self) -> Result<(u64, u16)> {
         #[repr(C, packed)]
         #[derive(Clone, Copy, Default, bytemuck::Zeroable, bytemuck::Pod)]
         struct Info {
@@ -74,10 +201,22 @@ impl Disk {
             );
 
             if *ret != 0 {
-                Err("could not read disk size")
-            } else {
-                Ok(info.absolute_sectors)
+                return Err("could not read disk size");
+            }
+
+            // some older BIOSes leave this zero, and mean 512
+            let sector_size = match info.bytes_per_sector {
+                0 => crate::MIN_SECTOR_SIZE,
+                n => n,
+            };
+            if sector_size < crate::MIN_SECTOR_SIZE
+                || sector_size > crate::MAX_SECTOR_SIZE
+                || !sector_size.is_power_of_two()
+            {
+                return Err("unsupported sector size");
             }
+
+            Ok((info.absolute_sectors, sector_size))
         }
     }
 
@@ -100,6 +239,14 @@ impl Disk {
         }
     }
 
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn sector_size(&self) -> u16 {
+        self.sector_size
+    }
+
     pub fn narrow(&self, start: u64, length: u64) -> Result<Self> {
         if start + length > self.length {
             return Err("narrowed region too large")?;
@@ -108,18 +255,50 @@ impl Disk {
             id: self.id,
             start: self.start + start,
             length: length,
+            sector_size: self.sector_size,
+            geometry: self.geometry,
         })
     }
 
-    pub fn read<'a>(
-        &self,
-        start: u64,
-        buffer: &'a mut [u8; crate::SECTOR_SIZE as usize],
-    ) -> Result<&'a [u8]> {
+    // reads one sector, and hands its sector_size() bytes to f
+    //
+    // the bytes are in a buffer shared by every read, so f can't read
+    // the disk again itself.
+    pub fn read<T>(&self, start: u64, f: impl FnOnce(&[u8]) -> Result<T>) -> Result<T> {
         if start >= self.length {
             return Err("read past end of disk");
         }
+        if CACHE_BUSY.swap(true, Ordering::SeqCst) {
+            return Err("sector buffer already in use");
+        }
+        let result = self.fill(start).and_then(f);
+        CACHE_BUSY.store(false, Ordering::SeqCst);
+        result
+    }
+
+    // make CACHE hold sector start, only going to the BIOS if it
+    // doesn't already
+    fn fill(&self, start: u64) -> Result<&'static [u8]> {
+        let key = (self.id, self.start + start);
+        unsafe {
+            if CACHE.holds != Some(key) {
+                CACHE.holds = None;
+                match self.geometry {
+                    Some(geometry) => self.read_chs(geometry, start, &mut CACHE.data)?,
+                    None => self.read_lba(start, &mut CACHE.data)?,
+                }
+                CACHE.holds = Some(key);
+            }
+            Ok(&CACHE.data[..self.sector_size as usize])
+        }
+    }
 
+    // read() with AH=42h
+    fn read_lba(
+        &self,
+        start: u64,
+        buffer: &mut [u8; crate::MAX_SECTOR_SIZE as usize],
+    ) -> Result<()> {
         unsafe {
             crate::real_asm!(
                 "push si",
@@ -133,7 +312,7 @@ impl Disk {
                 "mov [{0} + {ret}], ah",
                 "pop si",
 
-                realbuffer: [u8; crate::SECTOR_SIZE as usize] = alloc,
+                realbuffer: [u8; crate::MAX_SECTOR_SIZE as usize] = alloc,
                 data_addr = const memoffset::offset_of!(Dap, buffer),
                 dap: Dap = alloc Dap {
                     size: core::mem::size_of::<Dap>() as u8,
@@ -149,40 +328,176 @@ impl Disk {
             if *ret != 0 {
                 Err("could not read disk")
             } else {
-                buffer.copy_from_slice(&realbuffer[..]);
-                Ok(buffer)
+                let size = self.sector_size as usize;
+                buffer[..size].copy_from_slice(&realbuffer[..size]);
+                Ok(())
             }
         }
     }
 
+    // read() for BIOSes without LBA extensions, with AH=02h
+    fn read_chs(
+        &self,
+        geometry: Geometry,
+        start: u64,
+        buffer: &mut [u8; crate::MAX_SECTOR_SIZE as usize],
+    ) -> Result<()> {
+        let lba = self.start + start;
+        let track = lba / geometry.sectors as u64;
+        let sector = (lba % geometry.sectors as u64) as u16 + 1;
+        let head = (track % geometry.heads as u64) as u16;
+        let cylinder = track / geometry.heads as u64;
+        if cylinder >= 1024 {
+            return Err("read past what CHS can reach");
+        }
+        let cylinder = cylinder as u16;
+
+        unsafe {
+            crate::real_asm!(
+                "push es",
+                "push ebx",
+                "push ds",
+                "pop es",
+                "lea bx, [{0} + {realbuffer}]",
+                "mov ax, 0x0201",
+                "mov cx, [{0} + {cx}]",
+                "mov dh, [{0} + {head}]",
+                "mov dl, [{0} + {id}]",
+                "int 0x13",
+                "mov [{0} + {ret}], ah",
+                "pop ebx",
+                "pop es",
+
+                realbuffer: [u8; crate::MAX_SECTOR_SIZE as usize] = alloc,
+                // cylinder in ch, and its top two bits above the sector in cl
+                cx: u16 = alloc (cylinder << 8) | ((cylinder >> 2) & 0xc0) | sector,
+                head: u8 = alloc head as u8,
+                id: u8 = alloc self.id,
+                ret: u8 = alloc 1,
+            );
+
+            if *ret != 0 {
+                Err("could not read disk")
+            } else {
+                let size = self.sector_size as usize;
+                buffer[..size].copy_from_slice(&realbuffer[..size]);
+                Ok(())
+            }
+        }
+    }
+
+    // the El Torito specification packet for a drive, if it is a CD
+    // we booted from
+    fn boot_media(id: u8) -> Option<u8> {
+        #[repr(C, packed)]
+        #[derive(Clone, Copy, Default, bytemuck::Zeroable, bytemuck::Pod)]
+        struct Specification {
+            size: u8,
+            media: u8,
+            drive: u8,
+            controller: u8,
+            lba: u32,
+            device: u16,
+            buffer_segment: u16,
+            load_segment: u16,
+            sectors: u16,
+            chs: [u8; 3],
+        }
+
+        unsafe {
+            crate::real_asm!(
+                "push si",
+                "mov ax, 0x4b01",
+                "mov dl, [{0} + {id}]",
+                "lea si, [{0} + {spec}]",
+                "int 0x13",
+                "mov [{0} + {ret}], ah",
+                "sbb al, al",
+                "mov [{0} + {carry}], al",
+                "pop si",
+                spec: Specification = alloc Specification {
+                    size: core::mem::size_of::<Specification>() as u8,
+                    .. Default::default()
+                },
+                id: u8 = alloc id,
+                ret: u8 = alloc 1,
+                carry: u8 = alloc 1,
+            );
+
+            if *ret != 0 || *carry != 0 || spec.drive != id {
+                None
+            } else {
+                Some(spec.media)
+            }
+        }
+    }
+
+    // did we boot from a CD in El Torito no emulation mode?
+    pub fn boot_is_cdrom() -> bool {
+        Self::boot_media(boot_drive()).map_or(false, |media| media & 0x0f == 0)
+    }
+
     pub fn cursor(&self) -> DiskCursor {
         DiskCursor {
             disk: self.clone(),
             pos: 0,
-            lba_in_buffer: None,
-            buffer: [0; crate::SECTOR_SIZE as usize],
         }
     }
 
     pub fn read_table(&self) -> Result<PartitionedDisk> {
-        let mut buffer = [0; crate::SECTOR_SIZE as usize];
-        let mut table = crate::mbr::PartitionTable::new();
-        table.load_boot_sector(self.read(0, &mut buffer)?)?;
-        Ok(PartitionedDisk { disk: self.clone(), table })
+        let mut mbr = crate::mbr::PartitionTable::new();
+        // on larger sectors, the MBR is still just the first 512 bytes
+        self.read(0, |sector| {
+            mbr.load_boot_sector(&sector[..crate::MIN_SECTOR_SIZE as usize])
+        })?;
+
+        let table = if mbr.is_protective() {
+            let mut gpt = crate::gpt::PartitionTable::new();
+            gpt.load(self)?;
+            PartitionTable::Gpt(gpt)
+        } else {
+            PartitionTable::Mbr(mbr)
+        };
+
+        Ok(PartitionedDisk {
+            disk: self.clone(),
+            table,
+        })
     }
 }
 
 impl PartitionedDisk {
+    pub fn table(&self) -> &PartitionTable {
+        &self.table
+    }
+
     pub fn open(
         &mut self,
         id: usize,
     ) -> Result<fatfs::FileSystem<DiskCursor, fatfs::DefaultTimeProvider, fatfs::LossyOemCpConverter>>
     {
-        let start = self.table.table[id].first_lba as u64;
-        let length = self.table.table[id].sectors as u64;
-        if length == 0 {
-            return Err("partition does not exist");
-        }
+        let (start, length) = match self.table {
+            PartitionTable::Mbr(ref mbr) => {
+                let part = mbr.table.get(id).ok_or("partition does not exist")?;
+                if part.sectors == 0 {
+                    return Err("partition does not exist");
+                }
+                if !part.is_fat() {
+                    return Err("partition type is not FAT12, FAT16, or FAT32");
+                }
+                (part.first_lba as u64, part.sectors as u64)
+            }
+            PartitionTable::Gpt(ref gpt) => {
+                let part = gpt.table.get(id).ok_or("partition does not exist")?;
+                if !part.is_used() {
+                    return Err("partition does not exist");
+                }
+                if part.typ != crate::gpt::BASIC_DATA {
+                    return Err("partition type is not basic data");
+                }
+                (part.first_lba, part.sectors())
+            }
+        };
         let cur = self.disk.narrow(start, length)?.cursor();
         fatfs::FileSystem::new(cur, fatfs::FsOptions::new()).map_err(|_| "could not open fs")
     }
@@ -195,8 +510,9 @@ impl fatfs::IoBase for DiskCursor {
 impl fatfs::Read for DiskCursor {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
         // determine sector and local offset
-        let sector = self.pos / crate::SECTOR_SIZE as u64;
-        let offset = (self.pos % crate::SECTOR_SIZE as u64) as usize;
+        let sector_size = self.disk.sector_size as u64;
+        let sector = self.pos / sector_size;
+        let offset = (self.pos % sector_size) as usize;
 
         // is this EOF?
         if sector >= self.disk.length {
@@ -208,13 +524,14 @@ impl fatfs::Read for DiskCursor {
             return Err(());
         }
 
-        // read that sector!
-        if Some(sector) != self.lba_in_buffer {
-            self.disk.read(sector, &mut self.buffer).map_err(|_| ())?;
-            self.lba_in_buffer = Some(sector);
-        }
-        let amount = buf.len().min(self.buffer.len() - offset);
-        buf[..amount].copy_from_slice(&self.buffer[offset..offset + amount]);
+        // read that sector! the shared buffer caches it for next time
+        let amount = buf.len().min(sector_size as usize - offset);
+        self.disk
+            .read(sector, |data| {
+                buf[..amount].copy_from_slice(&data[offset..offset + amount]);
+                Ok(())
+            })
+            .map_err(|_| ())?;
         self.pos += amount as u64;
         Ok(amount)
     }
@@ -222,19 +539,20 @@ impl fatfs::Read for DiskCursor {
 
 impl fatfs::Seek for DiskCursor {
     fn seek(&mut self, pos: fatfs::SeekFrom) -> Result<u64, Self::Error> {
+        let sector_size = self.disk.sector_size as u64;
         match pos {
             fatfs::SeekFrom::Start(offset) => {
                 self.pos = offset;
             }
             fatfs::SeekFrom::End(offset) => {
-                self.pos = (self.disk.length as i64 * crate::SECTOR_SIZE as i64 + offset) as u64;
+                self.pos = ((self.disk.length * sector_size) as i64 + offset) as u64;
             }
             fatfs::SeekFrom::Current(offset) => {
                 self.pos = (self.pos as i64 + offset) as u64;
             }
         }
 
-        if self.disk.length * (crate::SECTOR_SIZE as u64) < self.pos {
+        if self.disk.length * sector_size < self.pos {
             // seek to negative offset, or past end
             return Err(());
         }
@@ -0,0 +1,191 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::disk::Disk;
+use crate::Result;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+
+// Microsoft basic data, used for FAT filesystems (EBD0A0A2-B9E5-4433-87C0-68B6B72699C7)
+pub const BASIC_DATA: [u8; 16] = [
+    0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7,
+];
+
+#[derive(Clone, Debug)]
+pub struct PartitionTable {
+    pub disk_guid: [u8; 16],
+    // only the first few entries are kept, but all are checksummed
+    pub table: [PartitionEntry; 4],
+}
+
+#[derive(Clone, Debug)]
+pub struct PartitionEntry {
+    pub typ: [u8; 16],
+    pub guid: [u8; 16],
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+}
+
+// bitwise CRC32, small rather than fast
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) const fn new() -> Self {
+        Self(!0)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 ^= b as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xedb8_8320 & mask);
+            }
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl PartitionTable {
+    pub const fn new() -> Self {
+        Self {
+            disk_guid: [0; 16],
+            table: [
+                PartitionEntry::empty(),
+                PartitionEntry::empty(),
+                PartitionEntry::empty(),
+                PartitionEntry::empty(),
+            ],
+        }
+    }
+
+    pub fn load(&mut self, disk: &Disk) -> Result<()> {
+        // primary header lives in sector 1, backup in the last sector
+        if self.load_header(disk, 1).is_ok() {
+            return Ok(());
+        }
+        self.load_header(disk, disk.length() - 1)
+            .map_err(|_| "no valid GPT header")
+    }
+
+    fn load_header(&mut self, disk: &Disk, lba: u64) -> Result<()> {
+        let (disk_guid, entries_lba, num_entries, entry_size, entries_crc) =
+            disk.read(lba, |header| {
+                if &header[0..8] != SIGNATURE {
+                    return Err("bad GPT signature");
+                }
+
+                let header_size = LittleEndian::read_u32(&header[12..]) as usize;
+                if header_size < MIN_HEADER_SIZE || header_size > header.len() {
+                    return Err("bad GPT header size");
+                }
+
+                // checksum is computed with the checksum field zeroed
+                let mut crc = Crc32::new();
+                crc.update(&header[..16]);
+                crc.update(&[0; 4]);
+                crc.update(&header[20..header_size]);
+                if crc.finish() != LittleEndian::read_u32(&header[16..]) {
+                    return Err("bad GPT header checksum");
+                }
+
+                if LittleEndian::read_u64(&header[24..]) != lba {
+                    return Err("GPT header in wrong place");
+                }
+
+                let mut disk_guid = [0; 16];
+                disk_guid.copy_from_slice(&header[56..72]);
+                Ok((
+                    disk_guid,
+                    LittleEndian::read_u64(&header[72..]),
+                    LittleEndian::read_u32(&header[80..]) as u64,
+                    LittleEndian::read_u32(&header[84..]) as usize,
+                    LittleEndian::read_u32(&header[88..]),
+                ))
+            })?;
+
+        // entries are 128 * 2^n bytes, so these never straddle sectors
+        if entry_size < MIN_ENTRY_SIZE
+            || !entry_size.is_power_of_two()
+            || entry_size > disk.sector_size() as usize
+        {
+            return Err("unsupported GPT entry size");
+        }
+
+        let mut table = Self::new().table;
+        let mut crc = Crc32::new();
+        let mut remaining = num_entries * entry_size as u64;
+        let mut lba = entries_lba;
+        let mut index = 0;
+        while remaining > 0 {
+            let amount = disk.read(lba, |data| {
+                let amount = remaining.min(data.len() as u64) as usize;
+                crc.update(&data[..amount]);
+                for entry in data[..amount].chunks(entry_size) {
+                    if let Some(slot) = table.get_mut(index) {
+                        *slot = PartitionEntry::read(entry);
+                    }
+                    index += 1;
+                }
+                Ok(amount)
+            })?;
+            remaining -= amount as u64;
+            lba += 1;
+        }
+
+        if crc.finish() != entries_crc {
+            return Err("bad GPT entry checksum");
+        }
+
+        self.disk_guid = disk_guid;
+        self.table = table;
+        Ok(())
+    }
+}
+
+impl PartitionEntry {
+    const fn empty() -> Self {
+        Self {
+            typ: [0; 16],
+            guid: [0; 16],
+            first_lba: 0,
+            last_lba: 0,
+            attributes: 0,
+        }
+    }
+
+    fn read(data: &[u8]) -> Self {
+        assert!(data.len() >= MIN_ENTRY_SIZE);
+        let mut typ = [0; 16];
+        let mut guid = [0; 16];
+        typ.copy_from_slice(&data[0..16]);
+        guid.copy_from_slice(&data[16..32]);
+        let first_lba = LittleEndian::read_u64(&data[32..]);
+        let last_lba = LittleEndian::read_u64(&data[40..]);
+        let attributes = LittleEndian::read_u64(&data[48..]);
+        Self {
+            typ,
+            guid,
+            first_lba,
+            last_lba,
+            attributes,
+        }
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.typ != [0; 16]
+    }
+
+    pub fn sectors(&self) -> u64 {
+        if self.is_used() {
+            self.last_lba + 1 - self.first_lba
+        } else {
+            0
+        }
+    }
+}
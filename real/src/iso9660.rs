@@ -0,0 +1,423 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use byteorder::{ByteOrder, LittleEndian};
+use fatfs::{Read, Seek, SeekFrom};
+
+use crate::disk::{Disk, DiskCursor};
+use crate::Result;
+
+// volume descriptors start at byte 32K, in 2048 byte sectors, no
+// matter what the logical block size is
+const DESCRIPTORS: u64 = 16 * 2048;
+const DESCRIPTOR_SIZE: usize = 2048;
+// never look further than this for the terminator
+const MAX_DESCRIPTORS: u64 = 32;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+pub const MAX_NAME: usize = 255;
+
+// volume descriptors and directory records are read into here, rather
+// than onto the small stack
+static mut SCRATCH: [u8; DESCRIPTOR_SIZE] = [0; DESCRIPTOR_SIZE];
+
+// set while SCRATCH is lent out
+static SCRATCH_BUSY: AtomicBool = AtomicBool::new(false);
+
+// which set of names the filesystem is read with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Names {
+    // upper case 8.3 names, matched without case
+    Plain,
+    // UCS-2 names from the Joliet supplementary volume
+    Joliet,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Extent {
+    // in bytes from the start of the disk
+    start: u64,
+    size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct FileSystem {
+    disk: Disk,
+    block_size: u64,
+    root: Extent,
+    names: Names,
+}
+
+pub struct Dir<'a> {
+    fs: &'a FileSystem,
+    extent: Extent,
+}
+
+pub struct DirIter<'a> {
+    fs: &'a FileSystem,
+    cursor: DiskCursor,
+    extent: Extent,
+    pos: u64,
+}
+
+#[derive(Clone)]
+pub struct DirEntry {
+    extent: Extent,
+    flags: u8,
+    names: Names,
+    name: [u8; MAX_NAME],
+    name_len: usize,
+}
+
+pub struct File {
+    cursor: DiskCursor,
+    extent: Extent,
+    pos: u64,
+}
+
+// a bounded buffer for building names in
+struct Name {
+    buf: [u8; MAX_NAME],
+    len: usize,
+}
+
+impl Name {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_NAME],
+            len: 0,
+        }
+    }
+
+    fn push_bytes(&mut self, data: &[u8]) {
+        let amount = data.len().min(MAX_NAME - self.len);
+        self.buf[self.len..self.len + amount].copy_from_slice(&data[..amount]);
+        self.len += amount;
+    }
+
+    fn push_char(&mut self, c: char) {
+        let mut utf8 = [0; 4];
+        let encoded = c.encode_utf8(&mut utf8);
+        if self.len + encoded.len() <= MAX_NAME {
+            self.push_bytes(encoded.as_bytes());
+        }
+    }
+
+    // drop the ";1" version, and the dot of an empty extension
+    fn strip_version(&mut self) {
+        if let Some(semi) = self.buf[..self.len].iter().position(|&c| c == b';') {
+            self.len = semi;
+        }
+        if self.len > 1 && self.buf[self.len - 1] == b'.' {
+            self.len -= 1;
+        }
+    }
+}
+
+// lend SCRATCH to f, which can't use it again itself
+fn with_scratch<T>(f: impl FnOnce(&mut [u8; DESCRIPTOR_SIZE]) -> Result<T>) -> Result<T> {
+    if SCRATCH_BUSY.swap(true, Ordering::SeqCst) {
+        return Err("scratch buffer already in use");
+    }
+    let result = f(unsafe { &mut SCRATCH });
+    SCRATCH_BUSY.store(false, Ordering::SeqCst);
+    result
+}
+
+fn read_at(cursor: &mut DiskCursor, offset: u64, buf: &mut [u8]) -> Result<()> {
+    cursor
+        .seek(SeekFrom::Start(offset))
+        .map_err(|_| "could not seek disk")?;
+    cursor.read_exact(buf).map_err(|_| "could not read disk")
+}
+
+impl FileSystem {
+    pub fn new(disk: &Disk) -> Result<Self> {
+        let mut cursor = disk.cursor();
+        let mut primary = None;
+        let mut joliet = None;
+
+        with_scratch(|descriptor| {
+            for i in 0..MAX_DESCRIPTORS {
+                read_at(
+                    &mut cursor,
+                    DESCRIPTORS + i * DESCRIPTOR_SIZE as u64,
+                    descriptor,
+                )?;
+                if &descriptor[1..6] != b"CD001" {
+                    return Err("not an ISO9660 filesystem");
+                }
+
+                let block_size = LittleEndian::read_u16(&descriptor[128..]) as u64;
+                match descriptor[0] {
+                    1 => primary = Some((block_size, Self::root(descriptor, block_size)?)),
+                    // the escape sequences for UCS-2 levels 1 through 3
+                    2 if &descriptor[88..90] == b"%/" && b"@CE".contains(&descriptor[90]) => {
+                        joliet = Some((block_size, Self::root(descriptor, block_size)?))
+                    }
+                    255 => break,
+                    _ => {}
+                }
+            }
+            Ok(())
+        })?;
+
+        let (block_size, root) = primary.ok_or("no primary volume descriptor")?;
+        let (block_size, root, names) = match joliet {
+            Some((block_size, root)) => (block_size, root, Names::Joliet),
+            None => (block_size, root, Names::Plain),
+        };
+        Ok(Self {
+            disk: disk.clone(),
+            block_size,
+            root,
+            names,
+        })
+    }
+
+    fn root(descriptor: &[u8], block_size: u64) -> Result<Extent> {
+        if block_size == 0 || !block_size.is_power_of_two() {
+            return Err("bad logical block size");
+        }
+        // the root directory record, which is always 34 bytes
+        Ok(Self::extent(&descriptor[156..190], block_size))
+    }
+
+    fn extent(record: &[u8], block_size: u64) -> Extent {
+        let attribute_blocks = record[1] as u64;
+        Extent {
+            start: (LittleEndian::read_u32(&record[2..]) as u64 + attribute_blocks) * block_size,
+            size: LittleEndian::read_u32(&record[10..]) as u64,
+        }
+    }
+
+    pub fn names(&self) -> Names {
+        self.names
+    }
+
+    pub fn root_dir(&self) -> Dir<'_> {
+        Dir {
+            fs: self,
+            extent: self.root,
+        }
+    }
+}
+
+impl<'a> Dir<'a> {
+    pub fn iter(&self) -> DirIter<'a> {
+        DirIter {
+            fs: self.fs,
+            cursor: self.fs.disk.cursor(),
+            extent: self.extent,
+            pos: 0,
+        }
+    }
+
+    fn find(&self, name: &str) -> Result<DirEntry> {
+        for entry in self.iter() {
+            let entry = entry?;
+            if entry.matches(name) {
+                return Ok(entry);
+            }
+        }
+        Err("file not found")
+    }
+
+    // walk down a path split by '/', returning the last component
+    fn walk<'p>(&self, path: &'p str) -> Result<(Dir<'a>, &'p str)> {
+        let mut dir = Dir {
+            fs: self.fs,
+            extent: self.extent,
+        };
+        let mut parts = path.split('/').filter(|p| !p.is_empty()).peekable();
+        while let Some(part) = parts.next() {
+            if parts.peek().is_none() {
+                return Ok((dir, part));
+            }
+            dir = dir.find(part)?.to_dir(self.fs)?;
+        }
+        Err("empty path")
+    }
+
+    pub fn open_dir(&self, path: &str) -> Result<Dir<'a>> {
+        let (dir, name) = self.walk(path)?;
+        dir.find(name)?.to_dir(self.fs)
+    }
+
+    pub fn open_file(&self, path: &str) -> Result<File> {
+        let (dir, name) = self.walk(path)?;
+        dir.find(name)?.to_file(self.fs)
+    }
+}
+
+impl<'a> DirIter<'a> {
+    fn read_record(&mut self, record: &mut [u8]) -> Result<Option<usize>> {
+        let block_size = self.fs.block_size;
+        loop {
+            if self.pos >= self.extent.size {
+                return Ok(None);
+            }
+
+            read_at(
+                &mut self.cursor,
+                self.extent.start + self.pos,
+                &mut record[..1],
+            )?;
+            let len = record[0] as usize;
+            if len == 0 {
+                // records never cross blocks, so the rest is padding
+                self.pos = (self.pos / block_size + 1) * block_size;
+                continue;
+            }
+            if len < 34 {
+                return Err("bad directory record");
+            }
+
+            read_at(
+                &mut self.cursor,
+                self.extent.start + self.pos,
+                &mut record[..len],
+            )?;
+            self.pos += len as u64;
+            return Ok(Some(len));
+        }
+    }
+
+    fn entry(&self, record: &[u8]) -> Result<Option<DirEntry>> {
+        let id_len = record[32] as usize;
+        if 33 + id_len > record.len() {
+            return Err("bad directory record");
+        }
+        let id = &record[33..33 + id_len];
+        if id == [0] || id == [1] {
+            // "." and ".."
+            return Ok(None);
+        }
+
+        let mut name = Name::new();
+        match self.fs.names {
+            Names::Plain => {
+                name.push_bytes(id);
+                name.strip_version();
+            }
+            Names::Joliet => {
+                let units = id.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]]));
+                for c in core::char::decode_utf16(units) {
+                    name.push_char(c.unwrap_or(core::char::REPLACEMENT_CHARACTER));
+                }
+                name.strip_version();
+            }
+        }
+
+        Ok(Some(DirEntry {
+            extent: FileSystem::extent(record, self.fs.block_size),
+            flags: record[25],
+            names: self.fs.names,
+            name: name.buf,
+            name_len: name.len,
+        }))
+    }
+}
+
+impl<'a> Iterator for DirIter<'a> {
+    type Item = Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        with_scratch(|record| loop {
+            let len = match self.read_record(record)? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            if let Some(entry) = self.entry(&record[..len])? {
+                return Ok(Some(entry));
+            }
+        })
+        .transpose()
+    }
+}
+
+impl DirEntry {
+    pub fn file_name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.extent.size
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self.names {
+            Names::Plain => self.file_name().eq_ignore_ascii_case(name),
+            Names::Joliet => self.file_name() == name,
+        }
+    }
+
+    pub fn to_dir<'a>(&self, fs: &'a FileSystem) -> Result<Dir<'a>> {
+        if !self.is_dir() {
+            return Err("not a directory");
+        }
+        Ok(Dir {
+            fs,
+            extent: self.extent,
+        })
+    }
+
+    pub fn to_file(&self, fs: &FileSystem) -> Result<File> {
+        if self.is_dir() {
+            return Err("not a file");
+        }
+        if self.flags & FLAG_MULTI_EXTENT != 0 {
+            return Err("multi-extent files are not supported");
+        }
+        Ok(File {
+            cursor: fs.disk.cursor(),
+            extent: self.extent,
+            pos: 0,
+        })
+    }
+}
+
+impl fatfs::IoBase for File {
+    type Error = ();
+}
+
+impl fatfs::Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let amount = (self.extent.size.saturating_sub(self.pos) as usize).min(buf.len());
+        if amount == 0 {
+            return Ok(0);
+        }
+        self.cursor
+            .seek(SeekFrom::Start(self.extent.start + self.pos))?;
+        let amount = self.cursor.read(&mut buf[..amount])?;
+        self.pos += amount as u64;
+        Ok(amount)
+    }
+}
+
+impl fatfs::Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.extent.size as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+
+        if pos < 0 || pos as u64 > self.extent.size {
+            // seek to negative offset, or past end
+            return Err(());
+        }
+
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,53 @@
+// the BIOS timer ticks about 18.2 times a second
+pub const TICKS_PER_SECOND: u32 = 18;
+
+// ticks since midnight, from the BIOS clock
+fn ticks() -> u32 {
+    unsafe {
+        crate::real_asm!(
+            "mov ah, 0x00",
+            "int 0x1a",
+            "mov [{0} + {ticks}], dx",
+            "mov [{0} + {ticks} + 2], cx",
+            ticks: u32 = alloc 0,
+        );
+        *ticks
+    }
+}
+
+// the next key in the keyboard buffer, without waiting for one
+pub fn key() -> Option<u16> {
+    unsafe {
+        crate::real_asm!(
+            "mov ah, 0x01",
+            "int 0x16",
+            "jz 2f",
+            "mov ah, 0x00",
+            "int 0x16",
+            "mov [{0} + {key}], ax",
+            "mov byte ptr [{0} + {ret}], 1",
+            "2:",
+            key: u16 = alloc 0,
+            ret: u8 = alloc 0,
+        );
+
+        if *ret != 0 {
+            Some(*key)
+        } else {
+            None
+        }
+    }
+}
+
+// wait up to the given number of ticks for a key
+//
+// the tick count wraps at midnight, which just ends the wait early.
+pub fn wait_for_key(timeout: u32) -> Option<u16> {
+    let start = ticks();
+    while ticks().wrapping_sub(start) < timeout {
+        if let Some(k) = key() {
+            return Some(k);
+        }
+    }
+    None
+}
@@ -6,7 +6,9 @@
 #![feature(const_fn_trait_bound)]
 #![feature(const_ptr_offset_from)]
 
-pub const SECTOR_SIZE: u16 = 512;
+// sector sizes are read from the BIOS, but buffers must be sized up front
+pub const MIN_SECTOR_SIZE: u16 = 512;
+pub const MAX_SECTOR_SIZE: u16 = 4096;
 
 type Result<T, E = &'static str> = core::result::Result<T, E>;
 
@@ -36,9 +38,19 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     }
 }
 
+// what stage2 hands to stage3, which can't see stage1's arguments
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Handoff {
+    pub boot_drive: u8,
+}
+
 mod trampoline;
 pub use trampoline::{set_trampoline, trampoline, Trampoline, Work, WorkOffset, WORK, WORK_SIZE};
 
 pub mod disk;
+pub mod gpt;
+pub mod iso9660;
+pub mod keyboard;
 pub mod mbr;
 pub mod video;
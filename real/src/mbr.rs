@@ -1,7 +1,22 @@
+use blue_layout::{backup_lba, BACKUP_MAGIC};
 use byteorder::{ByteOrder, LittleEndian};
 
+use crate::disk::Disk;
+use crate::gpt::Crc32;
 use crate::Result;
 
+// partition types that hold a FAT filesystem
+pub const FAT_TYPES: &[u8] = &[
+    0x01, // FAT12
+    0x04, // FAT16, under 32M
+    0x06, // FAT16
+    0x0b, // FAT32 with CHS
+    0x0c, // FAT32 with LBA
+    0x0e, // FAT16 with LBA
+];
+
+pub use blue_layout::BOOT_CODE_SIZE;
+
 #[derive(Clone, Debug)]
 pub struct PartitionTable {
     pub signature: u32,
@@ -52,6 +67,11 @@ impl PartitionTable {
 
         Ok(())
     }
+
+    /// is this MBR just protecting a GPT?
+    pub fn is_protective(&self) -> bool {
+        self.table.iter().any(|p| p.typ == 0xee)
+    }
 }
 
 impl PartitionEntry {
@@ -66,6 +86,10 @@ impl PartitionEntry {
         }
     }
 
+    pub fn is_fat(&self) -> bool {
+        FAT_TYPES.contains(&self.typ)
+    }
+
     fn read(data: &[u8]) -> Self {
         assert!(data.len() == 16);
         let status = data[0];
@@ -94,3 +118,24 @@ impl CHS {
         CHS(cylinder, head, sector)
     }
 }
+
+/// the boot code blue-tool replaced when it installed the loader, if it
+/// kept one
+pub fn read_backup(disk: &Disk) -> Result<Option<[u8; BOOT_CODE_SIZE]>> {
+    disk.read(backup_lba(disk.sector_size()), |sector| {
+        if &sector[..8] != BACKUP_MAGIC {
+            return Ok(None);
+        }
+
+        let boot_code = &sector[16..16 + BOOT_CODE_SIZE];
+        let mut crc = Crc32::new();
+        crc.update(boot_code);
+        if crc.finish() != LittleEndian::read_u32(&sector[8..]) {
+            return Err("bad boot code backup checksum");
+        }
+
+        let mut saved = [0; BOOT_CODE_SIZE];
+        saved.copy_from_slice(boot_code);
+        Ok(Some(saved))
+    })
+}
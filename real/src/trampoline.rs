@@ -66,7 +66,8 @@ pub unsafe fn trampoline(code16: unsafe extern "cdecl" fn()) {
     }
 }
 
-pub const WORK_SIZE: usize = 0x400;
+// room for a full sector buffer, plus everything else
+pub const WORK_SIZE: usize = 0x400 + crate::MAX_SECTOR_SIZE as usize;
 
 #[repr(C, align(0x100))]
 pub struct Work([u8; WORK_SIZE]);
@@ -11,6 +11,8 @@ pub fn printc(c: u8) {
         crate::real_asm!(
             "push ebx",
             "mov ax, [{ax}]",
+            // copy to the debug console, for emulators that have one
+            "out 0xe9, al",
             "mov ebx, 7",
             "int 0x10",
             "pop ebx",
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::loader::{Dir, FileSystem};
+use crate::manifest::FileEntry;
+
+/// split a path inside the filesystem into its components
+fn split_dest(dest: &str) -> Vec<&str> {
+    dest.split('/').filter(|p| !p.is_empty()).collect()
+}
+
+/// walk down from dir, creating directories as needed
+fn create_dirs<'a, 'b>(dir: &Dir<'a, 'b>, parts: &[&str]) -> anyhow::Result<Dir<'a, 'b>> {
+    let mut dir = dir.clone();
+    for d in parts {
+        dir = dir
+            .create_dir(d)
+            .with_context(|| format!("could not create directory {:?}", d))?;
+    }
+    Ok(dir)
+}
+
+/// convert seconds since the unix epoch to a FAT timestamp, in UTC
+pub fn fat_datetime(secs: i64) -> fatfs::DateTime {
+    // FAT can only represent 1980 through 2107
+    let secs = secs.clamp(315_532_800, 4_354_819_199);
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    // days to civil date, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    fatfs::DateTime::new(
+        fatfs::Date::new(year as u16, month as u16, day as u16),
+        fatfs::Time::new(
+            (rem / 3600) as u16,
+            (rem / 60 % 60) as u16,
+            (rem % 60) as u16,
+            0,
+        ),
+    )
+}
+
+pub fn unix_seconds(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// write data to a file at the path parts under dir
+fn write_file(
+    dir: &Dir,
+    parts: &[&str],
+    data: &[u8],
+    meta: Option<&std::fs::Metadata>,
+) -> anyhow::Result<()> {
+    let (name, parents) = parts
+        .split_last()
+        .ok_or_else(|| anyhow::anyhow!("empty destination path"))?;
+    let dir = create_dirs(dir, parents)?;
+
+    // FAT names ignore case, so this would overwrite another file
+    for entry in dir.iter() {
+        let existing = entry.context("could not read directory")?.file_name();
+        if existing != *name && existing.to_lowercase() == name.to_lowercase() {
+            anyhow::bail!(
+                "{} differs from {} only in case, which FAT can't tell apart",
+                parts.join("/"),
+                existing
+            );
+        }
+    }
+
+    let mut file = dir
+        .create_file(name)
+        .with_context(|| format!("could not create {}", parts.join("/")))?;
+    file.truncate()?;
+    file.write_all(data)
+        .with_context(|| format!("could not write {}", parts.join("/")))?;
+
+    // these must come after writing, or the write will stamp the file again
+    if let Some(meta) = meta {
+        if let Ok(modified) = meta.modified() {
+            let modified = fat_datetime(unix_seconds(modified));
+            let created = meta
+                .created()
+                .map(|t| fat_datetime(unix_seconds(t)))
+                .unwrap_or(modified);
+            let accessed = meta
+                .accessed()
+                .map(|t| fat_datetime(unix_seconds(t)))
+                .unwrap_or(modified);
+            file.set_created(created);
+            file.set_accessed(accessed.date);
+            file.set_modified(modified);
+        }
+    }
+
+    Ok(())
+}
+
+/// copy a host file or directory tree to the path parts under dir
+///
+/// host timestamps are kept if keep_times is set, otherwise the
+/// filesystem's clock is used
+fn copy_path(dir: &Dir, source: &Path, parts: &[&str], keep_times: bool) -> anyhow::Result<()> {
+    let meta = std::fs::metadata(source)
+        .with_context(|| format!("could not read {}", source.display()))?;
+
+    if !meta.is_dir() {
+        let data = std::fs::read(source)
+            .with_context(|| format!("could not read {}", source.display()))?;
+        let meta = if keep_times { Some(&meta) } else { None };
+        return write_file(dir, parts, &data, meta);
+    }
+
+    let dir = create_dirs(dir, parts)?;
+
+    // sorted, so that images come out the same every time
+    let mut children = std::fs::read_dir(source)
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("could not list {}", source.display()))?;
+    children.sort_by_key(|child| child.file_name());
+
+    // FAT names ignore case, so one of these would overwrite the other
+    let mut folded = HashMap::new();
+    for child in children.iter() {
+        let name = child.file_name().to_string_lossy().to_lowercase();
+        if let Some(other) = folded.insert(name, child.path()) {
+            anyhow::bail!(
+                "{} and {} differ only in case, which FAT can't tell apart",
+                other.display(),
+                child.path().display()
+            );
+        }
+    }
+
+    for child in children {
+        let name = child.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("{} is not valid unicode", child.path().display()))?;
+        copy_path(&dir, &child.path(), &[name], keep_times)?;
+    }
+
+    Ok(())
+}
+
+/// write a manifest file entry into fs, creating directories as needed
+///
+/// directory sources are copied recursively, with their timestamps
+/// unless keep_times is false
+pub fn copy_entry(fs: &FileSystem, entry: &FileEntry, keep_times: bool) -> anyhow::Result<()> {
+    let parts = split_dest(&entry.dest);
+    let root = fs.root_dir();
+
+    match (&entry.source, &entry.contents) {
+        (Some(source), _) => copy_path(&root, source, &parts, keep_times),
+        (None, Some(contents)) => write_file(&root, &parts, contents.as_bytes(), None),
+        (None, None) => write_file(&root, &parts, &[], None),
+    }
+}
@@ -0,0 +1,402 @@
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::Context;
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+
+/// container formats an image can be written in
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Raw,
+    Qcow2,
+    Vmdk,
+    Vhd,
+    Iso,
+}
+
+/// what goes in the container headers besides the data
+///
+/// these come from the build, so reproducible builds stay reproducible
+#[derive(Clone, Debug)]
+pub struct Identity {
+    /// unique id for the disk
+    pub id: [u8; 16],
+    /// creation time, in seconds since the unix epoch
+    pub time: i64,
+    /// file name of the container, which VMDK refers to itself by
+    pub name: String,
+}
+
+/// the raw image being converted, read a chunk at a time
+struct Raw<'a, R> {
+    f: &'a mut R,
+    size: u64,
+}
+
+impl<'a, R: Read + Seek> Raw<'a, R> {
+    fn new(f: &'a mut R) -> anyhow::Result<Self> {
+        let size = f.seek(SeekFrom::End(0))?;
+        Ok(Self { f, size })
+    }
+
+    fn chunks(&self, chunk_size: u64) -> u64 {
+        (self.size + chunk_size - 1) / chunk_size
+    }
+
+    /// read chunk index into buf, zero filling past the end
+    fn read(&mut self, index: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        let start = index * buf.len() as u64;
+        let amount = (self.size - start).min(buf.len() as u64) as usize;
+        self.f.seek(SeekFrom::Start(start))?;
+        self.f
+            .read_exact(&mut buf[..amount])
+            .context("could not read raw image")?;
+        buf[amount..].fill(0);
+        Ok(())
+    }
+
+    /// the indices of every chunk holding anything but zeros
+    fn allocated(&mut self, chunk_size: u64) -> anyhow::Result<Vec<u64>> {
+        let mut buf = vec![0; chunk_size as usize];
+        let mut allocated = Vec::new();
+        for index in 0..self.chunks(chunk_size) {
+            self.read(index, &mut buf)?;
+            if buf.iter().any(|&b| b != 0) {
+                allocated.push(index);
+            }
+        }
+        Ok(allocated)
+    }
+}
+
+/// write the raw image in raw as format into out
+///
+/// all-zero clusters are left out of the sparse formats
+pub fn convert<R, W>(raw: &mut R, out: &mut W, format: Format, id: &Identity) -> anyhow::Result<()>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    let mut raw = Raw::new(raw)?;
+    match format {
+        Format::Raw => {
+            raw.f.seek(SeekFrom::Start(0))?;
+            std::io::copy(raw.f, out)?;
+            Ok(())
+        }
+        Format::Qcow2 => qcow2(&mut raw, out),
+        Format::Vmdk => vmdk(&mut raw, out, id),
+        Format::Vhd => vhd(&mut raw, out, id),
+        Format::Iso => anyhow::bail!("hybrid ISO images are built in place, not converted"),
+    }
+}
+
+fn div_ceil(a: u64, b: u64) -> u64 {
+    (a + b - 1) / b
+}
+
+// qcow2 version 2, with 64K clusters and 16-bit refcounts
+const QCOW2_CLUSTER_BITS: u32 = 16;
+const QCOW2_CLUSTER: u64 = 1 << QCOW2_CLUSTER_BITS;
+const QCOW2_L2_ENTRIES: u64 = QCOW2_CLUSTER / 8;
+const QCOW2_REFCOUNTS: u64 = QCOW2_CLUSTER / 2;
+// refcount is exactly one, so the cluster can be written in place
+const QCOW2_COPIED: u64 = 1 << 63;
+
+fn qcow2<R: Read + Seek, W: Write + Seek>(raw: &mut Raw<R>, out: &mut W) -> anyhow::Result<()> {
+    let allocated = raw.allocated(QCOW2_CLUSTER)?;
+
+    let l1_size = div_ceil(raw.chunks(QCOW2_CLUSTER), QCOW2_L2_ENTRIES).max(1);
+    let l1_clusters = div_ceil(l1_size * 8, QCOW2_CLUSTER);
+    let mut l2_tables: Vec<u64> = allocated.iter().map(|c| c / QCOW2_L2_ENTRIES).collect();
+    l2_tables.dedup();
+
+    // the refcounts have to count themselves, so grow them until they fit
+    let fixed = 1 + l1_clusters + l2_tables.len() as u64 + allocated.len() as u64;
+    let (mut table_clusters, mut blocks) = (1, 1);
+    loop {
+        let total = fixed + table_clusters + blocks;
+        let need_blocks = div_ceil(total, QCOW2_REFCOUNTS);
+        let need_table = div_ceil(need_blocks * 8, QCOW2_CLUSTER);
+        if need_blocks == blocks && need_table == table_clusters {
+            break;
+        }
+        blocks = need_blocks;
+        table_clusters = need_table;
+    }
+
+    // header, L1, refcount table, refcount blocks, L2 tables, then data
+    let l1_offset = QCOW2_CLUSTER;
+    let table_offset = l1_offset + l1_clusters * QCOW2_CLUSTER;
+    let blocks_offset = table_offset + table_clusters * QCOW2_CLUSTER;
+    let l2_offset = blocks_offset + blocks * QCOW2_CLUSTER;
+    let data_offset = l2_offset + l2_tables.len() as u64 * QCOW2_CLUSTER;
+    let total = data_offset / QCOW2_CLUSTER + allocated.len() as u64;
+
+    let mut header = vec![0; QCOW2_CLUSTER as usize];
+    header[0..4].copy_from_slice(b"QFI\xfb");
+    BigEndian::write_u32(&mut header[4..], 2);
+    // no backing file at 8..20
+    BigEndian::write_u32(&mut header[20..], QCOW2_CLUSTER_BITS);
+    BigEndian::write_u64(&mut header[24..], raw.size);
+    // no encryption at 32
+    BigEndian::write_u32(&mut header[36..], l1_size as u32);
+    BigEndian::write_u64(&mut header[40..], l1_offset);
+    BigEndian::write_u64(&mut header[48..], table_offset);
+    BigEndian::write_u32(&mut header[56..], table_clusters as u32);
+    // no snapshots at 60..72
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&header)?;
+
+    let mut l1 = vec![0; (l1_clusters * QCOW2_CLUSTER) as usize];
+    for (i, &table) in l2_tables.iter().enumerate() {
+        let offset = l2_offset + i as u64 * QCOW2_CLUSTER;
+        BigEndian::write_u64(&mut l1[table as usize * 8..], offset | QCOW2_COPIED);
+    }
+    out.write_all(&l1)?;
+
+    let mut refcount_table = vec![0; (table_clusters * QCOW2_CLUSTER) as usize];
+    for i in 0..blocks {
+        let offset = blocks_offset + i * QCOW2_CLUSTER;
+        BigEndian::write_u64(&mut refcount_table[i as usize * 8..], offset);
+    }
+    out.write_all(&refcount_table)?;
+
+    let mut refcounts = vec![0; (blocks * QCOW2_CLUSTER) as usize];
+    for i in 0..total {
+        BigEndian::write_u16(&mut refcounts[i as usize * 2..], 1);
+    }
+    out.write_all(&refcounts)?;
+
+    let mut l2 = vec![0; (l2_tables.len() as u64 * QCOW2_CLUSTER) as usize];
+    for (n, &cluster) in allocated.iter().enumerate() {
+        let table = l2_tables
+            .binary_search(&(cluster / QCOW2_L2_ENTRIES))
+            .unwrap();
+        let entry = table * QCOW2_L2_ENTRIES as usize + (cluster % QCOW2_L2_ENTRIES) as usize;
+        let offset = data_offset + n as u64 * QCOW2_CLUSTER;
+        BigEndian::write_u64(&mut l2[entry * 8..], offset | QCOW2_COPIED);
+    }
+    out.write_all(&l2)?;
+
+    let mut buf = vec![0; QCOW2_CLUSTER as usize];
+    for &cluster in allocated.iter() {
+        raw.read(cluster, &mut buf)?;
+        out.write_all(&buf)?;
+    }
+
+    Ok(())
+}
+
+// monolithic sparse VMDK, with 64K grains
+const VMDK_GRAIN_SECTORS: u64 = 128;
+const VMDK_GRAIN: u64 = VMDK_GRAIN_SECTORS * 512;
+const VMDK_GT_ENTRIES: u64 = 512;
+const VMDK_DESCRIPTOR_SECTORS: u64 = 20;
+
+fn vmdk<R: Read + Seek, W: Write + Seek>(
+    raw: &mut Raw<R>,
+    out: &mut W,
+    id: &Identity,
+) -> anyhow::Result<()> {
+    let allocated = raw.allocated(VMDK_GRAIN)?;
+
+    let capacity = div_ceil(raw.size, 512);
+    let grains = raw.chunks(VMDK_GRAIN);
+    let tables = div_ceil(grains, VMDK_GT_ENTRIES);
+    let gd_sectors = div_ceil(tables * 4, 512);
+    let gt_sectors = VMDK_GT_ENTRIES * 4 / 512;
+
+    // header, descriptor, grain directory, grain tables, then grains
+    let gd_offset = 1 + VMDK_DESCRIPTOR_SECTORS;
+    let gt_offset = gd_offset + gd_sectors;
+    let overhead =
+        div_ceil(gt_offset + tables * gt_sectors, VMDK_GRAIN_SECTORS) * VMDK_GRAIN_SECTORS;
+
+    let mut header = Vec::with_capacity(512);
+    header.extend_from_slice(b"KDMV");
+    header.write_u32::<LittleEndian>(1)?; // version
+    header.write_u32::<LittleEndian>(1)?; // flags: valid newline test
+    header.write_u64::<LittleEndian>(capacity)?;
+    header.write_u64::<LittleEndian>(VMDK_GRAIN_SECTORS)?;
+    header.write_u64::<LittleEndian>(1)?; // descriptor offset
+    header.write_u64::<LittleEndian>(VMDK_DESCRIPTOR_SECTORS)?;
+    header.write_u32::<LittleEndian>(VMDK_GT_ENTRIES as u32)?;
+    header.write_u64::<LittleEndian>(0)?; // no redundant grain directory
+    header.write_u64::<LittleEndian>(gd_offset)?;
+    header.write_u64::<LittleEndian>(overhead)?;
+    header.extend_from_slice(&[0, b'\n', b' ', b'\r', b'\n']);
+    header.write_u16::<LittleEndian>(0)?; // no compression
+    header.resize(512, 0);
+
+    // the usual fake geometry for IDE disks
+    let cylinders = (capacity / (16 * 63)).min(16383);
+    let cid = LittleEndian::read_u32(&id.id);
+    let descriptor = format!(
+        "# Disk DescriptorFile\n\
+         version=1\n\
+         CID={:08x}\n\
+         parentCID=ffffffff\n\
+         createType=\"monolithicSparse\"\n\
+         \n\
+         # Extent description\n\
+         RW {} SPARSE \"{}\"\n\
+         \n\
+         # The Disk Data Base\n\
+         #DDB\n\
+         \n\
+         ddb.virtualHWVersion = \"4\"\n\
+         ddb.geometry.cylinders = \"{}\"\n\
+         ddb.geometry.heads = \"16\"\n\
+         ddb.geometry.sectors = \"63\"\n\
+         ddb.adapterType = \"ide\"\n",
+        cid, capacity, id.name, cylinders
+    );
+    let mut descriptor = descriptor.into_bytes();
+    descriptor.resize((VMDK_DESCRIPTOR_SECTORS * 512) as usize, 0);
+
+    let mut gd = vec![0; (gd_sectors * 512) as usize];
+    for i in 0..tables {
+        LittleEndian::write_u32(
+            &mut gd[i as usize * 4..],
+            (gt_offset + i * gt_sectors) as u32,
+        );
+    }
+
+    let mut gts = vec![0; (overhead - gt_offset) as usize * 512];
+    for (n, &grain) in allocated.iter().enumerate() {
+        let sector = overhead + n as u64 * VMDK_GRAIN_SECTORS;
+        let sector =
+            u32::try_from(sector).map_err(|_| anyhow::anyhow!("image too large for VMDK"))?;
+        LittleEndian::write_u32(&mut gts[grain as usize * 4..], sector);
+    }
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&header)?;
+    out.write_all(&descriptor)?;
+    out.write_all(&gd)?;
+    out.write_all(&gts)?;
+
+    let mut buf = vec![0; VMDK_GRAIN as usize];
+    for &grain in allocated.iter() {
+        raw.read(grain, &mut buf)?;
+        out.write_all(&buf)?;
+    }
+
+    Ok(())
+}
+
+// dynamic VHD, with the usual 2M blocks
+const VHD_BLOCK: u64 = 2 << 20;
+const VHD_BITMAP: u64 = 512;
+const VHD_TABLE_OFFSET: u64 = 512 + 1024;
+// VHD times count from 2000-01-01
+const VHD_EPOCH: i64 = 946_684_800;
+
+/// ones' complement of the byte sum, as VHD checksums its structures
+fn vhd_checksum(data: &[u8]) -> u32 {
+    !data.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32))
+}
+
+/// disk geometry from the VHD spec, or the all-ones geometry that tells
+/// readers to trust the size field if the size can't be expressed exactly
+fn vhd_geometry(size: u64) -> (u16, u8, u8) {
+    let sectors = (size / 512).min(65535 * 16 * 255);
+    let (spt, heads, cyl_heads) = if sectors >= 65535 * 16 * 63 {
+        (255, 16, sectors / 255)
+    } else {
+        let mut spt = 17;
+        let mut cyl_heads = sectors / spt;
+        let mut heads = ((cyl_heads + 1023) / 1024).max(4);
+        if cyl_heads >= heads * 1024 || heads > 16 {
+            spt = 31;
+            heads = 16;
+            cyl_heads = sectors / spt;
+        }
+        if cyl_heads >= heads * 1024 {
+            spt = 63;
+            heads = 16;
+            cyl_heads = sectors / spt;
+        }
+        (spt, heads, cyl_heads)
+    };
+    let cylinders = cyl_heads / heads;
+
+    if cylinders * heads * spt * 512 == size {
+        (cylinders as u16, heads as u8, spt as u8)
+    } else {
+        (65535, 16, 255)
+    }
+}
+
+fn vhd<R: Read + Seek, W: Write + Seek>(
+    raw: &mut Raw<R>,
+    out: &mut W,
+    id: &Identity,
+) -> anyhow::Result<()> {
+    let allocated = raw.allocated(VHD_BLOCK)?;
+    let blocks = raw.chunks(VHD_BLOCK);
+    let table_size = div_ceil(blocks * 4, 512) * 512;
+    let data_offset = VHD_TABLE_OFFSET + table_size;
+
+    let mut footer = Vec::with_capacity(512);
+    footer.extend_from_slice(b"conectix");
+    footer.write_u32::<BigEndian>(2)?; // features: reserved, always set
+    footer.write_u32::<BigEndian>(0x0001_0000)?; // version
+    footer.write_u64::<BigEndian>(512)?; // dynamic header offset
+    footer.write_u32::<BigEndian>((id.time - VHD_EPOCH).clamp(0, u32::MAX as i64) as u32)?;
+    footer.extend_from_slice(b"blue");
+    footer.write_u32::<BigEndian>(0x0001_0000)?; // creator version
+    footer.extend_from_slice(b"Wi2k");
+    footer.write_u64::<BigEndian>(raw.size)?; // original size
+    footer.write_u64::<BigEndian>(raw.size)?; // current size
+    let (cylinders, heads, spt) = vhd_geometry(raw.size);
+    footer.write_u16::<BigEndian>(cylinders)?;
+    footer.push(heads);
+    footer.push(spt);
+    footer.write_u32::<BigEndian>(3)?; // dynamic disk
+    footer.write_u32::<BigEndian>(0)?; // checksum, filled in below
+    footer.extend_from_slice(&id.id);
+    footer.resize(512, 0);
+    let checksum = vhd_checksum(&footer);
+    BigEndian::write_u32(&mut footer[64..], checksum);
+
+    let mut header = Vec::with_capacity(1024);
+    header.extend_from_slice(b"cxsparse");
+    header.write_u64::<BigEndian>(u64::MAX)?; // unused data offset
+    header.write_u64::<BigEndian>(VHD_TABLE_OFFSET)?;
+    header.write_u32::<BigEndian>(0x0001_0000)?; // version
+    header.write_u32::<BigEndian>(blocks as u32)?;
+    header.write_u32::<BigEndian>(VHD_BLOCK as u32)?;
+    header.write_u32::<BigEndian>(0)?; // checksum, filled in below
+    header.resize(1024, 0); // no parent
+    let checksum = vhd_checksum(&header);
+    BigEndian::write_u32(&mut header[36..], checksum);
+
+    let mut table = vec![0xff; table_size as usize];
+    for (n, &block) in allocated.iter().enumerate() {
+        let offset = data_offset + n as u64 * (VHD_BITMAP + VHD_BLOCK);
+        let sector =
+            u32::try_from(offset / 512).map_err(|_| anyhow::anyhow!("image too large for VHD"))?;
+        BigEndian::write_u32(&mut table[block as usize * 4..], sector);
+    }
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&footer)?;
+    out.write_all(&header)?;
+    out.write_all(&table)?;
+
+    // every sector in a stored block is marked present
+    let bitmap = vec![0xff; VHD_BITMAP as usize];
+    let mut buf = vec![0; VHD_BLOCK as usize];
+    for &block in allocated.iter() {
+        raw.read(block, &mut buf)?;
+        out.write_all(&bitmap)?;
+        out.write_all(&buf)?;
+    }
+
+    // and a copy of the footer at the very end
+    out.write_all(&footer)?;
+
+    Ok(())
+}
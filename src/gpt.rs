@@ -0,0 +1,316 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::Context;
+use byteorder::{ByteOrder, LittleEndian};
+
+pub const SIGNATURE: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x0001_0000;
+const HEADER_SIZE: u32 = 92;
+const ENTRY_SIZE: u32 = 128;
+const NUM_ENTRIES: u32 = 128;
+// the most partition entry array we will read, far more than any real
+// GPT needs, so a bad header can't ask for all of memory
+const MAX_ENTRIES_SIZE: u64 = 1 << 20;
+
+// sectors taken up by the partition entry array
+const fn entry_sectors(sector_size: u16) -> u64 {
+    ((ENTRY_SIZE * NUM_ENTRIES + sector_size as u32 - 1) / sector_size as u32) as u64
+}
+
+/// GUIDs in their on-disk, mixed-endian byte order
+pub type Guid = [u8; 16];
+
+/// Microsoft basic data partition, used for FAT filesystems
+pub const BASIC_DATA: Guid = guid(0xebd0a0a2, 0xb9e5, 0x4433, 0x87c0, 0x68b6b72699c7);
+
+/// EFI system partition, which is always FAT
+pub const EFI_SYSTEM: Guid = guid(0xc12a7328, 0xf81f, 0x11d2, 0xba4b, 0x00a0c93ec93b);
+
+/// the MBR partition type of a protective MBR entry
+pub const PROTECTIVE_TYPE: u8 = 0xee;
+
+/// build a GUID from its usual textual fields
+pub const fn guid(a: u32, b: u16, c: u16, d: u16, e: u64) -> Guid {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    let d = d.to_be_bytes();
+    let e = e.to_be_bytes();
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], e[2], e[3], e[4], e[5], e[6],
+        e[7],
+    ]
+}
+
+/// make a random (version 4) GUID
+pub fn random_guid<R: rand::Rng>(rng: &mut R) -> Guid {
+    let mut g: Guid = rng.gen();
+    g[7] = (g[7] & 0x0f) | 0x40;
+    g[8] = (g[8] & 0x3f) | 0x80;
+    g
+}
+
+/// format a GUID the usual way
+pub fn display_guid(g: &Guid) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        LittleEndian::read_u32(&g[0..]),
+        LittleEndian::read_u16(&g[4..]),
+        LittleEndian::read_u16(&g[6..]),
+        g[8],
+        g[9],
+        g[10],
+        g[11],
+        g[12],
+        g[13],
+        g[14],
+        g[15],
+    )
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub type_guid: Guid,
+    pub guid: Guid,
+    pub first_lba: u64,
+    /// inclusive
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Gpt {
+    pub disk_guid: Guid,
+    pub partitions: Vec<Partition>,
+}
+
+impl Partition {
+    pub fn is_used(&self) -> bool {
+        self.type_guid != [0; 16]
+    }
+
+    fn write_into(&self, buf: &mut [u8]) {
+        buf[0..16].copy_from_slice(&self.type_guid);
+        buf[16..32].copy_from_slice(&self.guid);
+        LittleEndian::write_u64(&mut buf[32..], self.first_lba);
+        LittleEndian::write_u64(&mut buf[40..], self.last_lba);
+        LittleEndian::write_u64(&mut buf[48..], self.attributes);
+        for (i, c) in self.name.encode_utf16().take(36).enumerate() {
+            LittleEndian::write_u16(&mut buf[56 + 2 * i..], c);
+        }
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        let mut type_guid = [0; 16];
+        let mut guid = [0; 16];
+        type_guid.copy_from_slice(&buf[0..16]);
+        guid.copy_from_slice(&buf[16..32]);
+        let name: Vec<u16> = buf[56..128]
+            .chunks(2)
+            .map(LittleEndian::read_u16)
+            .take_while(|&c| c != 0)
+            .collect();
+        Self {
+            type_guid,
+            guid,
+            first_lba: LittleEndian::read_u64(&buf[32..]),
+            last_lba: LittleEndian::read_u64(&buf[40..]),
+            attributes: LittleEndian::read_u64(&buf[48..]),
+            name: String::from_utf16_lossy(&name),
+        }
+    }
+}
+
+impl Gpt {
+    /// first sector usable by partitions
+    pub const fn first_usable(sector_size: u16) -> u64 {
+        2 + entry_sectors(sector_size)
+    }
+
+    /// last sector usable by partitions, inclusive
+    pub const fn last_usable(disk_sectors: u64, sector_size: u16) -> u64 {
+        disk_sectors - 2 - entry_sectors(sector_size)
+    }
+
+    /// write a protective MBR, primary and backup GPT
+    pub fn write_into<F>(
+        &self,
+        f: &mut F,
+        disk_signature: [u8; 4],
+        sector_size: u16,
+    ) -> anyhow::Result<()>
+    where
+        F: Read + Write + Seek,
+    {
+        if self.partitions.len() > NUM_ENTRIES as usize {
+            anyhow::bail!("too many GPT partitions: {}", self.partitions.len());
+        }
+
+        let disk_sectors = f.seek(SeekFrom::End(0))? / sector_size as u64;
+        if disk_sectors < 2 * Self::first_usable(sector_size) {
+            anyhow::bail!("disk too small for a GPT");
+        }
+
+        let mut mbr = mbrman::MBR::new_from(f, sector_size as u32, disk_signature)
+            .context("could not create protective MBR")?;
+        mbr[1] = mbrman::MBRPartitionEntry {
+            boot: false,
+            sys: PROTECTIVE_TYPE,
+            first_chs: mbrman::CHS::empty(),
+            last_chs: mbrman::CHS::empty(),
+            starting_lba: 1,
+            sectors: (disk_sectors - 1).min(u32::MAX as u64) as u32,
+        };
+        mbr.write_into(f)
+            .context("could not write protective MBR")?;
+
+        let mut entries = vec![0; (ENTRY_SIZE * NUM_ENTRIES) as usize];
+        for (part, buf) in self
+            .partitions
+            .iter()
+            .zip(entries.chunks_mut(ENTRY_SIZE as usize))
+        {
+            part.write_into(buf);
+        }
+        let entries_crc = crc32fast::hash(&entries);
+
+        let primary = 1;
+        let backup = disk_sectors - 1;
+        let backup_entries = backup - entry_sectors(sector_size);
+        let header = |current, other, entries_lba| {
+            self.header(
+                disk_sectors,
+                current,
+                other,
+                entries_lba,
+                entries_crc,
+                sector_size,
+            )
+        };
+        let sector_size = sector_size as u64;
+
+        // backup first, so a crash leaves the old primary in place
+        f.seek(SeekFrom::Start(backup_entries * sector_size))?;
+        f.write_all(&entries)?;
+        f.seek(SeekFrom::Start(backup * sector_size))?;
+        f.write_all(&header(backup, primary, backup_entries))?;
+
+        f.seek(SeekFrom::Start((primary + 1) * sector_size))?;
+        f.write_all(&entries)?;
+        f.seek(SeekFrom::Start(primary * sector_size))?;
+        f.write_all(&header(primary, backup, primary + 1))?;
+
+        Ok(())
+    }
+
+    /// a header sector for the copy of the table at sector current
+    fn header(
+        &self,
+        disk_sectors: u64,
+        current: u64,
+        other: u64,
+        entries_lba: u64,
+        entries_crc: u32,
+        sector_size: u16,
+    ) -> Vec<u8> {
+        let mut header = vec![0; sector_size as usize];
+        header[0..8].copy_from_slice(SIGNATURE);
+        LittleEndian::write_u32(&mut header[8..], REVISION);
+        LittleEndian::write_u32(&mut header[12..], HEADER_SIZE);
+        // header CRC at 16 is filled in last
+        LittleEndian::write_u64(&mut header[24..], current);
+        LittleEndian::write_u64(&mut header[32..], other);
+        LittleEndian::write_u64(&mut header[40..], Self::first_usable(sector_size));
+        LittleEndian::write_u64(
+            &mut header[48..],
+            Self::last_usable(disk_sectors, sector_size),
+        );
+        header[56..72].copy_from_slice(&self.disk_guid);
+        LittleEndian::write_u64(&mut header[72..], entries_lba);
+        LittleEndian::write_u32(&mut header[80..], NUM_ENTRIES);
+        LittleEndian::write_u32(&mut header[84..], ENTRY_SIZE);
+        LittleEndian::write_u32(&mut header[88..], entries_crc);
+        let crc = crc32fast::hash(&header[..HEADER_SIZE as usize]);
+        LittleEndian::write_u32(&mut header[16..], crc);
+        header
+    }
+
+    /// read the GPT, falling back to the backup if the primary is damaged
+    pub fn read_from<R: Read + Seek>(f: &mut R, sector_size: u16) -> anyhow::Result<Self> {
+        let disk_sectors = f.seek(SeekFrom::End(0))? / sector_size as u64;
+        Self::read_header(f, 1, sector_size).or_else(|primary| {
+            Self::read_header(f, disk_sectors - 1, sector_size)
+                .map_err(|_| primary.context("primary and backup GPT are both damaged"))
+        })
+    }
+
+    fn read_header<R: Read + Seek>(f: &mut R, lba: u64, sector_size: u16) -> anyhow::Result<Self> {
+        let mut header = vec![0; sector_size as usize];
+        f.seek(SeekFrom::Start(lba * sector_size as u64))?;
+        f.read_exact(&mut header)?;
+
+        if &header[0..8] != SIGNATURE {
+            anyhow::bail!("bad GPT signature at sector {}", lba);
+        }
+        let header_size = LittleEndian::read_u32(&header[12..]) as usize;
+        if header_size < HEADER_SIZE as usize || header_size > header.len() {
+            anyhow::bail!("bad GPT header size at sector {}", lba);
+        }
+        let crc = LittleEndian::read_u32(&header[16..]);
+        LittleEndian::write_u32(&mut header[16..], 0);
+        if crc32fast::hash(&header[..header_size]) != crc {
+            anyhow::bail!("bad GPT header checksum at sector {}", lba);
+        }
+
+        let mut disk_guid = [0; 16];
+        disk_guid.copy_from_slice(&header[56..72]);
+        let entries_lba = LittleEndian::read_u64(&header[72..]);
+        let num_entries = LittleEndian::read_u32(&header[80..]);
+        let entry_size = LittleEndian::read_u32(&header[84..]);
+        let entries_crc = LittleEndian::read_u32(&header[88..]);
+        if entry_size < ENTRY_SIZE || entry_size % 8 != 0 {
+            anyhow::bail!("bad GPT entry size at sector {}", lba);
+        }
+
+        let entries_size = num_entries as u64 * entry_size as u64;
+        if entries_size > MAX_ENTRIES_SIZE {
+            anyhow::bail!(
+                "GPT at sector {} has {} bytes of entries, more than the {} allowed",
+                lba,
+                entries_size,
+                MAX_ENTRIES_SIZE
+            );
+        }
+        let disk_size = f.seek(SeekFrom::End(0))?;
+        let entries_end = entries_lba
+            .checked_mul(sector_size as u64)
+            .and_then(|start| start.checked_add(entries_size));
+        if entries_end.map_or(true, |end| end > disk_size) {
+            anyhow::bail!("GPT at sector {} has entries past the end of the disk", lba);
+        }
+
+        let mut entries = vec![0; entries_size as usize];
+        f.seek(SeekFrom::Start(entries_lba * sector_size as u64))?;
+        f.read_exact(&mut entries)?;
+        if crc32fast::hash(&entries) != entries_crc {
+            anyhow::bail!("bad GPT entry checksum at sector {}", lba);
+        }
+
+        let partitions = entries
+            .chunks(entry_size as usize)
+            .map(Partition::read)
+            .collect();
+
+        Ok(Self {
+            disk_guid,
+            partitions,
+        })
+    }
+}
+
+/// does this MBR only exist to protect a GPT?
+pub fn is_protective(mbr: &mbrman::MBR) -> bool {
+    mbr.iter()
+        .any(|(_, part)| part.is_used() && part.sys == PROTECTIVE_TYPE)
+}
@@ -0,0 +1,566 @@
+use std::convert::TryFrom;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use rand::{Rng, SeedableRng};
+
+use crate::format::{Format, Identity};
+use crate::gpt::{self, Gpt};
+use crate::loader::{self, BlocklistError, Clock};
+use crate::manifest::{FileEntry, Manifest, Partition, PartitionScheme};
+
+/// settings that make a build come out the same every time
+#[derive(Clone, Copy, Debug)]
+pub struct Reproducible {
+    /// seeds the disk signature, GUIDs, and volume IDs
+    pub seed: u64,
+    /// every file is stamped with this time, in seconds since the unix epoch
+    pub timestamp: i64,
+}
+
+/// what a lower layer failed with, kept as the source of an ImageError
+pub type Source = Box<dyn std::error::Error + Send + Sync>;
+
+/// everything that can go wrong building an image
+#[derive(Debug, thiserror::Error)]
+pub enum ImageError {
+    #[error("invalid manifest: {0}")]
+    Manifest(String),
+    #[error("image size {size} is not a multiple of {sector_size}")]
+    UnalignedSize { size: u64, sector_size: u16 },
+    #[error("image size {0} is too small")]
+    TooSmall(u64),
+    #[error("partition {number} size {size} is not a multiple of {sector_size}")]
+    UnalignedPartition {
+        number: usize,
+        size: u64,
+        sector_size: u16,
+    },
+    #[error("partition {0} does not fit on the disk")]
+    PartitionTooLarge(usize),
+    #[error("image too large for MBR, use a GPT instead")]
+    TooLargeForMbr,
+    #[error("hybrid ISO images need an MBR partition table")]
+    IsoNeedsMbr,
+    #[error("hybrid ISO images keep their ISO9660 volume in the gap, so stage2 can't be embedded")]
+    IsoEmbedStage2,
+    #[error(transparent)]
+    Blocklist(#[from] BlocklistError),
+    #[error("could not {} {}", .action, .path.display())]
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("could not write the partition table")]
+    PartitionTable(#[source] Source),
+    #[error("could not format partition {number}")]
+    Format {
+        number: usize,
+        #[source]
+        source: Source,
+    },
+    #[error("could not install the loader")]
+    Install(#[source] Source),
+    #[error("could not copy files into partition {number}")]
+    Files {
+        number: usize,
+        #[source]
+        source: Source,
+    },
+    #[error("could not write the ISO9660 filesystem to {}", .path.display())]
+    Iso {
+        path: PathBuf,
+        #[source]
+        source: Source,
+    },
+    #[error("could not convert the image to {}", .path.display())]
+    Convert {
+        path: PathBuf,
+        #[source]
+        source: Source,
+    },
+}
+
+impl ImageError {
+    fn io(action: &'static str, path: &Path) -> impl FnOnce(std::io::Error) -> Self {
+        let path = path.to_owned();
+        move |source| Self::Io {
+            action,
+            path,
+            source,
+        }
+    }
+}
+
+/// builds a disk image with the loader installed, for example
+///
+/// ```no_run
+/// use blue_tool::image::ImageBuilder;
+/// use blue_tool::manifest::Partition;
+///
+/// ImageBuilder::new()
+///     .size(64 << 20)
+///     .partition(Partition::loader())
+///     .file("kernel.elf", "target/kernel.elf")
+///     .write_to("disk.img")?;
+/// # Ok::<(), blue_tool::image::ImageError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct ImageBuilder {
+    manifest: Manifest,
+    reproducible: Option<Reproducible>,
+    format: Format,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageBuilder {
+    /// a 16M raw MBR image, with no partitions yet
+    pub fn new() -> Self {
+        Self::from_manifest(Manifest::new(16 << 20))
+    }
+
+    /// start from everything a manifest describes
+    pub fn from_manifest(manifest: Manifest) -> Self {
+        Self {
+            manifest,
+            reproducible: None,
+            format: Format::Raw,
+        }
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// total image size in bytes
+    pub fn size(mut self, size: u64) -> Self {
+        self.manifest.size = size;
+        self
+    }
+
+    pub fn table(mut self, table: PartitionScheme) -> Self {
+        self.manifest.table = table;
+        self
+    }
+
+    /// logical sector size in bytes, 512 or 4096
+    pub fn sector_size(mut self, sector_size: u16) -> Self {
+        self.manifest.sector_size = sector_size;
+        self
+    }
+
+    /// start partitions on multiples of this many sectors
+    pub fn alignment(mut self, alignment: u64) -> Self {
+        self.manifest.alignment = alignment;
+        self
+    }
+
+    /// put stage2 in the gap before the first partition, where nothing
+    /// done to the filesystem can move it
+    pub fn embed_stage2(mut self, embed: bool) -> Self {
+        self.manifest.embed_stage2 = embed;
+        self
+    }
+
+    /// add a partition after any already added
+    pub fn partition(mut self, partition: Partition) -> Self {
+        self.manifest.partitions.push(partition);
+        self
+    }
+
+    /// copy a host file or directory tree to dest in the loader partition
+    pub fn file(mut self, dest: &str, source: impl Into<PathBuf>) -> Self {
+        self.loader_files().push(FileEntry {
+            dest: dest.to_owned(),
+            source: Some(source.into()),
+            contents: None,
+        });
+        self
+    }
+
+    /// write contents to dest in the loader partition
+    pub fn contents(mut self, dest: &str, contents: impl Into<String>) -> Self {
+        self.loader_files().push(FileEntry {
+            dest: dest.to_owned(),
+            source: None,
+            contents: Some(contents.into()),
+        });
+        self
+    }
+
+    /// the files of the loader partition, which is added with the
+    /// defaults if there are no partitions yet
+    fn loader_files(&mut self) -> &mut Vec<FileEntry> {
+        if self.manifest.partitions.is_empty() {
+            self.manifest.partitions.push(Partition::loader());
+        }
+        let loader = self.manifest.loader_partition();
+        &mut self.manifest.partitions[loader].files
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// make the same inputs always give the same image
+    pub fn reproducible(mut self, reproducible: Reproducible) -> Self {
+        self.reproducible = Some(reproducible);
+        self
+    }
+
+    /// build the image at path, replacing anything already there
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<(), ImageError> {
+        if self.manifest.partitions.is_empty() {
+            let mut manifest = self.manifest.clone();
+            manifest.partitions.push(Partition::loader());
+            build(path.as_ref(), &manifest, self.reproducible, self.format)
+        } else {
+            build(
+                path.as_ref(),
+                &self.manifest,
+                self.reproducible,
+                self.format,
+            )
+        }
+    }
+}
+
+/// place each partition on the disk, as (first sector, sector count)
+fn layout(manifest: &Manifest, disk_sectors: u64) -> Result<Vec<(u64, u64)>, ImageError> {
+    let align = manifest.alignment;
+    let sector_size = manifest.sector_size as u64;
+    let end = match manifest.table {
+        PartitionScheme::Mbr => disk_sectors,
+        PartitionScheme::Gpt => Gpt::last_usable(disk_sectors, manifest.sector_size) + 1,
+    };
+
+    let mut placed = Vec::with_capacity(manifest.partitions.len());
+    let mut next = align;
+    for (i, part) in manifest.partitions.iter().enumerate() {
+        let start = (next + align - 1) / align * align;
+        let sectors = match part.size {
+            Some(size) => {
+                if size % sector_size != 0 {
+                    return Err(ImageError::UnalignedPartition {
+                        number: i + 1,
+                        size,
+                        sector_size: manifest.sector_size,
+                    });
+                }
+                size / sector_size
+            }
+            None => end.saturating_sub(start),
+        };
+        if sectors == 0 || start + sectors > end {
+            return Err(ImageError::PartitionTooLarge(i + 1));
+        }
+        placed.push((start, sectors));
+        next = start + sectors;
+    }
+
+    Ok(placed)
+}
+
+/// the usual 255 head, 63 sector translation for MBR partition entries.
+/// BIOSes guess a disk's geometry from these, which matters for the
+/// loader's CHS reads when there are no LBA extensions.
+const CHS_HEADS: u64 = 255;
+const CHS_SECTORS: u64 = 63;
+
+/// the CHS address of a sector, or the largest one past 1024 cylinders
+fn chs(lba: u64) -> mbrman::CHS {
+    let track = lba / CHS_SECTORS;
+    let cylinder = track / CHS_HEADS;
+    if cylinder > 1023 {
+        return mbrman::CHS {
+            cylinder: 1023,
+            head: (CHS_HEADS - 1) as u8,
+            sector: CHS_SECTORS as u8,
+        };
+    }
+    mbrman::CHS {
+        cylinder: cylinder as u16,
+        head: (track % CHS_HEADS) as u8,
+        sector: (lba % CHS_SECTORS + 1) as u8,
+    }
+}
+
+/// build a fresh image at path, as described by manifest, in format
+///
+/// with reproducible set, the same inputs always give the same image
+fn build(
+    path: &Path,
+    manifest: &Manifest,
+    reproducible: Option<Reproducible>,
+    format: Format,
+) -> Result<(), ImageError> {
+    // ChaCha8 rather than StdRng, which may change between rand versions
+    let (mut rng, clock, time) = match reproducible {
+        Some(r) => (
+            rand_chacha::ChaCha8Rng::seed_from_u64(r.seed),
+            Clock::fixed(r.timestamp),
+            r.timestamp,
+        ),
+        None => (
+            rand_chacha::ChaCha8Rng::from_entropy(),
+            Clock::default(),
+            crate::files::unix_seconds(SystemTime::now()),
+        ),
+    };
+
+    manifest
+        .validate()
+        .map_err(|e| ImageError::Manifest(format!("{:#}", e)))?;
+    match format {
+        Format::Raw => return build_raw(path, manifest, &mut rng, clock).map(drop),
+        Format::Iso => return build_iso(path, manifest, &mut rng, clock, time),
+        _ => {}
+    }
+
+    // build raw next to the output, then pack it into the container
+    let raw_path = temp_raw(path)?;
+    let result = build_raw(&raw_path, manifest, &mut rng, clock).and_then(|_| {
+        let identity = Identity {
+            id: gpt::random_guid(&mut rng),
+            time,
+            name: path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        pack(&raw_path, path, format, &identity)
+    });
+    std::fs::remove_file(&raw_path).ok();
+    result
+}
+
+/// build a raw image at path, then make it a hybrid that also boots from CD
+fn build_iso(
+    path: &Path,
+    manifest: &Manifest,
+    rng: &mut rand_chacha::ChaCha8Rng,
+    clock: Clock,
+    time: i64,
+) -> Result<(), ImageError> {
+    // the GPT backup header has to stay at the end of the disk, where
+    // the ISO9660 files go
+    if manifest.table != PartitionScheme::Mbr {
+        return Err(ImageError::IsoNeedsMbr);
+    }
+    if manifest.embed_stage2 {
+        return Err(ImageError::IsoEmbedStage2);
+    }
+
+    let ranges = build_raw(path, manifest, rng, clock)?;
+    let loader = manifest.loader_partition();
+    let (fs_start, fs_end) = ranges[loader];
+    let volume_id = manifest.partitions[loader]
+        .label
+        .as_deref()
+        .unwrap_or("BLUE");
+    crate::iso::hybridize(
+        path,
+        fs_start,
+        fs_end,
+        ranges[0].0,
+        manifest.size,
+        volume_id,
+        time,
+    )
+    .map_err(|e| ImageError::Iso {
+        path: path.to_owned(),
+        source: e.into(),
+    })
+}
+
+/// write the raw image at raw_path into a container at path
+fn pack(
+    raw_path: &Path,
+    path: &Path,
+    format: Format,
+    identity: &Identity,
+) -> Result<(), ImageError> {
+    let mut raw = std::fs::File::open(raw_path).map_err(ImageError::io("open", raw_path))?;
+    let out = std::fs::File::create(path).map_err(ImageError::io("create", path))?;
+    let mut out = std::io::BufWriter::new(out);
+    crate::format::convert(&mut raw, &mut out, format, identity).map_err(|e| {
+        ImageError::Convert {
+            path: path.to_owned(),
+            source: e.into(),
+        }
+    })?;
+    out.flush().map_err(ImageError::io("write", path))?;
+    Ok(())
+}
+
+/// a new empty file next to path to build the raw image in, with a name
+/// nothing else can already have
+fn temp_raw(path: &Path) -> Result<PathBuf, ImageError> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut n = 0;
+    loop {
+        let temp = path.with_file_name(format!(".{}.{}-{}.raw.tmp", name, std::process::id(), n));
+        match std::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+        {
+            Ok(_) => return Ok(temp),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(ImageError::io("create", &temp)(e)),
+        }
+    }
+}
+
+/// build a raw image at path, returning where each partition went as
+/// byte offsets (start, end)
+fn build_raw(
+    path: &Path,
+    manifest: &Manifest,
+    rng: &mut rand_chacha::ChaCha8Rng,
+    clock: Clock,
+) -> Result<Vec<(u64, u64)>, ImageError> {
+    let sector_size = manifest.sector_size;
+    if manifest.size % sector_size as u64 != 0 {
+        return Err(ImageError::UnalignedSize {
+            size: manifest.size,
+            sector_size,
+        });
+    }
+
+    let disk_sectors = manifest.size / sector_size as u64;
+    if disk_sectors < 2 * Gpt::first_usable(sector_size) {
+        return Err(ImageError::TooSmall(manifest.size));
+    }
+    let placed = layout(manifest, disk_sectors)?;
+
+    let f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(ImageError::io("create", path))?;
+    f.set_len(manifest.size)
+        .map_err(ImageError::io("resize", path))?;
+    let mut f = fscommon::BufStream::new(f);
+
+    match manifest.table {
+        PartitionScheme::Mbr => {
+            let mut mbr = mbrman::MBR::new_from(&mut f, sector_size as u32, rng.gen())
+                .map_err(|e| ImageError::PartitionTable(e.into()))?;
+            for (i, (part, &(start, sectors))) in
+                manifest.partitions.iter().zip(placed.iter()).enumerate()
+            {
+                mbr[i + 1] = mbrman::MBRPartitionEntry {
+                    boot: part.active,
+                    sys: part.filesystem.mbr_type(
+                        sectors * sector_size as u64,
+                        (start + sectors) * sector_size as u64,
+                    ),
+                    first_chs: chs(start),
+                    last_chs: chs(start + sectors - 1),
+                    starting_lba: u32::try_from(start).map_err(|_| ImageError::TooLargeForMbr)?,
+                    sectors: u32::try_from(sectors).map_err(|_| ImageError::TooLargeForMbr)?,
+                };
+            }
+            mbr.write_into(&mut f)
+                .map_err(|e| ImageError::PartitionTable(e.into()))?;
+        }
+        PartitionScheme::Gpt => {
+            let mut table = Gpt {
+                disk_guid: gpt::random_guid(rng),
+                partitions: Vec::new(),
+            };
+            for (part, &(start, sectors)) in manifest.partitions.iter().zip(placed.iter()) {
+                table.partitions.push(gpt::Partition {
+                    type_guid: gpt::BASIC_DATA,
+                    guid: gpt::random_guid(rng),
+                    first_lba: start,
+                    last_lba: start + sectors - 1,
+                    attributes: 0,
+                    name: part.label.clone().unwrap_or_default(),
+                });
+            }
+            table
+                .write_into(&mut f, rng.gen(), sector_size)
+                .map_err(|e| ImageError::PartitionTable(e.into()))?;
+        }
+    }
+
+    let ranges: Vec<(u64, u64)> = placed
+        .iter()
+        .map(|&(start, sectors)| {
+            let start = start * sector_size as u64;
+            (start, start + sectors * sector_size as u64)
+        })
+        .collect();
+
+    for (i, (part, &(fs_start, fs_end))) in
+        manifest.partitions.iter().zip(ranges.iter()).enumerate()
+    {
+        let mut label = [0; 11];
+        if let Some(ref text) = part.label {
+            label[..text.len()].copy_from_slice(text.as_bytes());
+        }
+
+        let format_error = |source: Source| ImageError::Format {
+            number: i + 1,
+            source,
+        };
+        let mut fatimg = fscommon::StreamSlice::new(&mut f, fs_start, fs_end)
+            .map_err(|e| format_error(e.into()))?;
+        fatfs::format_volume(
+            &mut fatfs::StdIoWrapper::new(&mut fatimg),
+            fatfs::FormatVolumeOptions::new()
+                .fat_type(part.filesystem.fat_type())
+                .bytes_per_sector(sector_size)
+                .volume_id(part.volume_id.unwrap_or_else(|| rng.gen()))
+                .volume_label(label),
+        )
+        .map_err(|e| format_error(e.into()))?;
+    }
+
+    let (fs_start, fs_end) = ranges[manifest.loader_partition()];
+    crate::install::install_into(
+        &mut f,
+        fs_start,
+        fs_end,
+        sector_size,
+        clock,
+        manifest.embed_stage2,
+    )
+    .map_err(|e| match e.downcast::<BlocklistError>() {
+        Ok(e) => ImageError::Blocklist(e),
+        Err(e) => ImageError::Install(e.into()),
+    })?;
+
+    for (i, (part, &(fs_start, fs_end))) in
+        manifest.partitions.iter().zip(ranges.iter()).enumerate()
+    {
+        let copy = |f: &mut loader::Image| -> anyhow::Result<()> {
+            let fs = loader::open_fs_with(f, fs_start, fs_end, clock)?;
+            for entry in part.files.iter() {
+                crate::files::copy_entry(&fs, entry, !clock.is_fixed())?;
+            }
+            Ok(())
+        };
+        copy(&mut f).map_err(|e| ImageError::Files {
+            number: i + 1,
+            source: e.into(),
+        })?;
+    }
+
+    f.flush().map_err(ImageError::io("write", path))?;
+
+    Ok(ranges)
+}
@@ -0,0 +1,352 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Context;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Serialize;
+
+use crate::gpt::{self, Gpt};
+use crate::install::FAT_TYPES;
+use crate::loader::{self, Dir, Image};
+
+#[derive(Serialize, Debug)]
+struct Report {
+    sector_size: u16,
+    disk_signature: u32,
+    table: &'static str,
+    disk_guid: Option<String>,
+    partitions: Vec<PartitionReport>,
+    blocklist: Vec<BlocklistEntry>,
+}
+
+#[derive(Serialize, Debug)]
+struct PartitionReport {
+    number: usize,
+    #[serde(rename = "type")]
+    typ: String,
+    active: bool,
+    first_lba: u64,
+    sectors: u64,
+    first_chs: Option<[u16; 3]>,
+    last_chs: Option<[u16; 3]>,
+    name: Option<String>,
+    filesystem: Option<FsReport>,
+    /// why a partition that looks like FAT could not be read
+    filesystem_error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct BlocklistEntry {
+    lba: u32,
+    sectors: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct FsReport {
+    fat_type: String,
+    volume_label: String,
+    volume_id: u32,
+    cluster_size: u32,
+    total_clusters: u32,
+    free_clusters: u32,
+    root: Vec<EntryReport>,
+}
+
+#[derive(Serialize, Debug)]
+struct EntryReport {
+    name: String,
+    directory: bool,
+    size: u64,
+    /// (first cluster, cluster count) runs
+    clusters: Vec<(u32, u32)>,
+    children: Vec<EntryReport>,
+}
+
+/// where the data region starts in a FAT filesystem, in bytes from its
+/// start, or None if it doesn't look like one
+fn fat_data_start(f: &mut Image, fs_start: u64) -> anyhow::Result<Option<u64>> {
+    let mut bpb = [0; 512];
+    f.seek(SeekFrom::Start(fs_start))?;
+    f.read_exact(&mut bpb)
+        .context("could not read boot sector")?;
+
+    let bytes_per_sector = LittleEndian::read_u16(&bpb[11..]) as u64;
+    let reserved = LittleEndian::read_u16(&bpb[14..]) as u64;
+    let fats = bpb[16] as u64;
+    let root_entries = LittleEndian::read_u16(&bpb[17..]) as u64;
+    let fat_size = match LittleEndian::read_u16(&bpb[22..]) {
+        0 => LittleEndian::read_u32(&bpb[36..]) as u64,
+        n => n as u64,
+    };
+    if bytes_per_sector == 0 || bpb[510..512] != [0x55, 0xaa] {
+        return Ok(None);
+    }
+    let root_sectors = (root_entries * 32 + bytes_per_sector - 1) / bytes_per_sector;
+
+    Ok(Some(
+        (reserved + fats * fat_size + root_sectors) * bytes_per_sector,
+    ))
+}
+
+fn walk(
+    dir: &Dir,
+    fs_start: u64,
+    data_start: u64,
+    cluster_size: u32,
+) -> anyhow::Result<Vec<EntryReport>> {
+    let mut entries = Vec::new();
+    for entry in dir.iter() {
+        let entry = entry.context("could not read directory")?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let mut report = EntryReport {
+            name,
+            directory: entry.is_dir(),
+            size: entry.len(),
+            clusters: Vec::new(),
+            children: Vec::new(),
+        };
+
+        if entry.is_dir() {
+            report.children = walk(&entry.to_dir(), fs_start, data_start, cluster_size)?;
+        } else {
+            let extents = loader::file_extents(&mut entry.to_file(), fs_start)?;
+            let cluster_size = cluster_size as u64;
+            for (offset, size) in extents {
+                let first = (offset - fs_start - data_start) / cluster_size + 2;
+                let count = (size as u64 + cluster_size - 1) / cluster_size;
+                report.clusters.push((first as u32, count as u32));
+            }
+        }
+
+        entries.push(report);
+    }
+    Ok(entries)
+}
+
+/// describe the FAT filesystem in a partition, or None if there is
+/// nothing there that looks like one
+fn inspect_fs(
+    f: &mut Image,
+    first_lba: u64,
+    sectors: u64,
+    sector_size: u16,
+) -> anyhow::Result<Option<FsReport>> {
+    let fs_start = first_lba * sector_size as u64;
+    let fs_end = fs_start + sectors * sector_size as u64;
+
+    let data_start = match fat_data_start(f, fs_start)? {
+        Some(data_start) => data_start,
+        None => return Ok(None),
+    };
+    let fs = loader::open_fs(f, fs_start, fs_end)?;
+    let stats = fs.stats().context("could not read FAT")?;
+    let cluster_size = fs.cluster_size();
+    let root = walk(&fs.root_dir(), fs_start, data_start, cluster_size)?;
+
+    Ok(Some(FsReport {
+        fat_type: format!("{:?}", fs.fat_type()),
+        volume_label: fs.volume_label(),
+        volume_id: fs.volume_id(),
+        cluster_size,
+        total_clusters: stats.total_clusters(),
+        free_clusters: stats.free_clusters(),
+        root,
+    }))
+}
+
+/// inspect_fs, with any error kept as text for the report, for
+/// partitions whose type says they are FAT
+fn inspect_fs_or_error(
+    f: &mut Image,
+    is_fat: bool,
+    first_lba: u64,
+    sectors: u64,
+    sector_size: u16,
+) -> (Option<FsReport>, Option<String>) {
+    if !is_fat {
+        return (None, None);
+    }
+    match inspect_fs(f, first_lba, sectors, sector_size) {
+        Ok(fs) => (fs, None),
+        Err(e) => (None, Some(format!("{:#}", e))),
+    }
+}
+
+fn chs(c: &mbrman::CHS) -> Option<[u16; 3]> {
+    if c.is_empty() {
+        None
+    } else {
+        Some([c.cylinder, c.head as u16, c.sector as u16])
+    }
+}
+
+fn report(f: &mut Image) -> anyhow::Result<Report> {
+    let sector_size = loader::detect_sector_size(f)?;
+    let mbr =
+        mbrman::MBR::read_from(f, sector_size as u32).context("could not read partition table")?;
+
+    let mut report = Report {
+        sector_size,
+        disk_signature: u32::from_le_bytes(mbr.header.disk_signature),
+        table: "mbr",
+        disk_guid: None,
+        partitions: Vec::new(),
+        blocklist: Vec::new(),
+    };
+
+    if gpt::is_protective(&mbr) {
+        let table = Gpt::read_from(f, sector_size).context("could not read GPT")?;
+        report.table = "gpt";
+        report.disk_guid = Some(gpt::display_guid(&table.disk_guid));
+        for (i, part) in table.partitions.iter().enumerate() {
+            if !part.is_used() {
+                continue;
+            }
+            let sectors = part.last_lba + 1 - part.first_lba;
+            let is_fat = [gpt::BASIC_DATA, gpt::EFI_SYSTEM].contains(&part.type_guid);
+            let (filesystem, filesystem_error) =
+                inspect_fs_or_error(f, is_fat, part.first_lba, sectors, sector_size);
+            report.partitions.push(PartitionReport {
+                number: i + 1,
+                typ: gpt::display_guid(&part.type_guid),
+                active: false,
+                first_lba: part.first_lba,
+                sectors,
+                first_chs: None,
+                last_chs: None,
+                name: Some(part.name.clone()),
+                filesystem,
+                filesystem_error,
+            });
+        }
+    } else {
+        for (i, part) in mbr.iter() {
+            if !part.is_used() {
+                continue;
+            }
+            let (filesystem, filesystem_error) = inspect_fs_or_error(
+                f,
+                FAT_TYPES.contains(&part.sys),
+                part.starting_lba as u64,
+                part.sectors as u64,
+                sector_size,
+            );
+            report.partitions.push(PartitionReport {
+                number: i,
+                typ: format!("0x{:02x}", part.sys),
+                active: part.boot,
+                first_lba: part.starting_lba as u64,
+                sectors: part.sectors as u64,
+                first_chs: chs(&part.first_chs),
+                last_chs: chs(&part.last_chs),
+                name: None,
+                filesystem,
+                filesystem_error,
+            });
+        }
+    }
+
+    report.blocklist = loader::read_blocklist(f)
+        .context("could not read blocklist")?
+        .into_iter()
+        .map(|(lba, sectors)| BlocklistEntry { lba, sectors })
+        .collect();
+
+    Ok(report)
+}
+
+fn print_entries(entries: &[EntryReport], depth: usize) {
+    for entry in entries {
+        let indent = "  ".repeat(depth);
+        if entry.directory {
+            println!("{}{}/", indent, entry.name);
+            print_entries(&entry.children, depth + 1);
+        } else {
+            let chain: Vec<String> = entry
+                .clusters
+                .iter()
+                .map(|(first, count)| format!("{}+{}", first, count))
+                .collect();
+            println!(
+                "{}{} ({} bytes) clusters {}",
+                indent,
+                entry.name,
+                entry.size,
+                chain.join(", ")
+            );
+        }
+    }
+}
+
+fn print_report(r: &Report) {
+    println!("sector size: {}", r.sector_size);
+    println!("disk signature: {:08x}", r.disk_signature);
+    if let Some(ref guid) = r.disk_guid {
+        println!("disk guid: {}", guid);
+    }
+
+    println!("{} partitions:", r.table);
+    for part in r.partitions.iter() {
+        println!(
+            "  {}: type {}{} lba {} sectors {}",
+            part.number,
+            part.typ,
+            if part.active { " (active)" } else { "" },
+            part.first_lba,
+            part.sectors,
+        );
+        if let (Some(first), Some(last)) = (part.first_chs, part.last_chs) {
+            println!("     chs {:?} to {:?}", first, last);
+        }
+        if let Some(ref name) = part.name {
+            println!("     name {:?}", name);
+        }
+        if let Some(ref fs) = part.filesystem {
+            println!(
+                "     {} label {:?} id {:08x} cluster size {} clusters {} free {}",
+                fs.fat_type,
+                fs.volume_label,
+                fs.volume_id,
+                fs.cluster_size,
+                fs.total_clusters,
+                fs.free_clusters,
+            );
+            print_entries(&fs.root, 3);
+        }
+        if let Some(ref error) = part.filesystem_error {
+            println!("     unreadable filesystem: {}", error);
+        }
+    }
+
+    println!("stage2 blocklist:");
+    for entry in r.blocklist.iter() {
+        println!(
+            "  lba {} sectors {} (bytes {}..{})",
+            entry.lba,
+            entry.sectors,
+            entry.lba as u64 * r.sector_size as u64,
+            (entry.lba as u64 + entry.sectors as u64) * r.sector_size as u64,
+        );
+    }
+}
+
+/// print the layout of an image: partitions, filesystems, and blocklist
+pub fn inspect(path: &Path, json: bool) -> anyhow::Result<()> {
+    let f =
+        std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut f = fscommon::BufStream::new(f);
+
+    let report = report(&mut f).with_context(|| format!("could not inspect {}", path.display()))?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+
+    Ok(())
+}
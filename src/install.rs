@@ -0,0 +1,351 @@
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::gpt::{self, Gpt};
+use crate::loader::{self, Backup, Clock, Image};
+
+// MBR partition types we know how to boot from
+pub(crate) const FAT_TYPES: &[u8] = &[
+    0x01, // FAT12
+    0x04, // FAT16, under 32M
+    0x06, // FAT16
+    0x0b, // FAT32 with CHS
+    0x0c, // FAT32 with LBA
+    0x0e, // FAT16 with LBA
+];
+
+/// find the FAT partition to install into, as a byte range
+///
+/// on MBR disks, prefers the active partition if there is more than
+/// one candidate. partition numbers start at 1.
+pub fn find_partition(
+    f: &mut Image,
+    partition: Option<usize>,
+    sector_size: u16,
+) -> anyhow::Result<(u64, u64)> {
+    let mbr =
+        mbrman::MBR::read_from(f, sector_size as u32).context("could not read partition table")?;
+
+    let (first_lba, sectors) = if gpt::is_protective(&mbr) {
+        let table = Gpt::read_from(f, sector_size)?;
+        let part = match partition {
+            Some(index) => table
+                .partitions
+                .get(index.wrapping_sub(1))
+                .filter(|part| part.is_used())
+                .ok_or_else(|| anyhow::anyhow!("partition {} does not exist", index))?,
+            None => table
+                .partitions
+                .iter()
+                .find(|part| part.type_guid == gpt::BASIC_DATA)
+                .ok_or_else(|| anyhow::anyhow!("no basic data partition found"))?,
+        };
+        (part.first_lba, part.last_lba + 1 - part.first_lba)
+    } else {
+        let index = match partition {
+            Some(index) if (1..=4).contains(&index) => index,
+            Some(index) => anyhow::bail!("partition {} is out of range 1-4", index),
+            None => {
+                let candidates: Vec<_> = mbr
+                    .iter()
+                    .filter(|(_, part)| part.is_used() && FAT_TYPES.contains(&part.sys))
+                    .collect();
+
+                candidates
+                    .iter()
+                    .find(|(_, part)| part.boot)
+                    .or_else(|| candidates.first())
+                    .map(|(i, _)| *i)
+                    .ok_or_else(|| anyhow::anyhow!("no FAT partition found"))?
+            }
+        };
+        if !mbr[index].is_used() {
+            anyhow::bail!("partition {} does not exist", index);
+        }
+        (mbr[index].starting_lba as u64, mbr[index].sectors as u64)
+    };
+
+    let start = first_lba * sector_size as u64;
+    Ok((start, start + sectors * sector_size as u64))
+}
+
+/// the first sector of the lowest partition, if there are any
+fn first_partition_lba(f: &mut Image, sector_size: u16) -> anyhow::Result<Option<u64>> {
+    let mbr =
+        mbrman::MBR::read_from(f, sector_size as u32).context("could not read partition table")?;
+    Ok(if gpt::is_protective(&mbr) {
+        Gpt::read_from(f, sector_size)?
+            .partitions
+            .iter()
+            .filter(|part| part.is_used())
+            .map(|part| part.first_lba)
+            .min()
+    } else {
+        mbr.iter()
+            .filter(|(_, part)| part.is_used())
+            .map(|(_, part)| part.starting_lba as u64)
+            .min()
+    })
+}
+
+/// remove a file from the root of fs, if it is there at all
+fn remove_if_present(fs: &loader::FileSystem, name: &str) -> anyhow::Result<()> {
+    match fs.root_dir().remove(name) {
+        Ok(()) | Err(fatfs::Error::NotFound) => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("could not remove {}", name)),
+    }
+}
+
+/// install the loader into the FAT filesystem between fs_start and fs_end
+///
+/// any boot code already there is saved in the gap before the first
+/// partition, unless it is empty or a stage1 of our own. reinstalling
+/// keeps the backup from the first install.
+///
+/// with embed_stage2, stage2 goes in that gap too, after the backup,
+/// so that nothing done to the filesystem can move it out from under
+/// stage1. stage3 stays in the filesystem either way.
+pub fn install_into(
+    f: &mut Image,
+    fs_start: u64,
+    fs_end: u64,
+    sector_size: u16,
+    clock: Clock,
+    embed_stage2: bool,
+) -> anyhow::Result<()> {
+    let current = loader::read_boot_code(f)?;
+    let boot_code = match loader::read_backup(f, sector_size)? {
+        Some(backup) => Some(backup.boot_code),
+        None if loader::is_stage1(&current) || current.iter().all(|&b| b == 0) => None,
+        None => Some(current),
+    };
+
+    let backup_lba = loader::backup_lba(sector_size);
+    let gap_end = first_partition_lba(f, sector_size)?.unwrap_or(u64::MAX);
+    if boot_code.is_some() && gap_end <= backup_lba {
+        anyhow::bail!(
+            "no room to back up the boot code at sector {}, before the first partition",
+            backup_lba
+        );
+    }
+
+    let blocklist = if embed_stage2 {
+        {
+            let fs = loader::open_fs_with(f, fs_start, fs_end, clock)?;
+            loader::copy_stage3(&fs, loader::LOADER_STAGE3_NAME)?;
+            // a stage2 file from an earlier install would only go stale
+            remove_if_present(&fs, loader::LOADER_STAGE2_NAME)?;
+        }
+        let start = loader::embed_lbas(sector_size)[0];
+        loader::embed_stage2(f, sector_size, start, gap_end)?
+    } else {
+        let fs = loader::open_fs_with(f, fs_start, fs_end, clock)?;
+        loader::copy_stages(&fs, fs_start, sector_size)?
+    };
+
+    loader::write_stage1(f, &blocklist, sector_size)?;
+    if let Some(boot_code) = boot_code {
+        let installed_crc = crc32fast::hash(&loader::read_boot_code(f)?);
+        loader::write_backup(
+            f,
+            sector_size,
+            &Backup {
+                boot_code,
+                installed_crc,
+            },
+        )?;
+    }
+    f.flush().context("could not flush image")?;
+
+    Ok(())
+}
+
+/// install the loader into an existing image, leaving everything
+/// but the boot code and the loader files untouched
+pub fn install(path: &Path, partition: Option<usize>, embed_stage2: bool) -> anyhow::Result<()> {
+    let f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("could not open {}", path.display()))?;
+    let mut f = fscommon::BufStream::new(f);
+
+    let sector_size = loader::detect_sector_size(&mut f)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let (fs_start, fs_end) = find_partition(&mut f, partition, sector_size)
+        .with_context(|| format!("could not find a partition in {}", path.display()))?;
+    install_into(
+        &mut f,
+        fs_start,
+        fs_end,
+        sector_size,
+        Clock::default(),
+        embed_stage2,
+    )
+    .with_context(|| format!("could not install loader into {}", path.display()))
+}
+
+// names the new loader files are written under during an upgrade
+const STAGE2_TEMP_NAME: &str = "blue-loader-stage2.new";
+const STAGE3_TEMP_NAME: &str = "blue-loader-stage3.new";
+
+/// replace the file name in the root of fs with the one at temp
+fn swap_in(fs: &loader::FileSystem, temp: &str, name: &str) -> anyhow::Result<()> {
+    remove_if_present(fs, name)?;
+    let root = fs.root_dir();
+    root.rename(temp, &root, name)
+        .with_context(|| format!("could not rename {} to {}", temp, name))
+}
+
+/// replace the loader already in the FAT filesystem between fs_start
+/// and fs_end with this blue-tool's
+///
+/// the new stage2 and stage3 are written under temporary names, and
+/// only swapped in once both are complete. stage1 is rewritten last,
+/// once everything else has been synced to file, and until then it
+/// still loads the old stage2, whose sectors are left alone even after
+/// its file is gone. embedded is where an embedded stage2 starts, if
+/// it is one; the new one goes in the other embedding slot, before
+/// anything in the filesystem changes.
+fn upgrade_into(
+    f: &mut Image,
+    file: &std::fs::File,
+    fs_start: u64,
+    fs_end: u64,
+    sector_size: u16,
+    embedded: Option<u64>,
+) -> anyhow::Result<()> {
+    {
+        let fs = loader::open_fs(f, fs_start, fs_end)?;
+        if fs.root_dir().open_file(loader::LOADER_STAGE3_NAME).is_err() {
+            anyhow::bail!(
+                "{} is missing, so there is no loader to upgrade",
+                loader::LOADER_STAGE3_NAME
+            );
+        }
+    }
+
+    // a new embedded stage2 goes in whichever slot the old one isn't
+    // in, so the gap has to hold both
+    let embedded_blocklist = match embedded {
+        Some(current) => {
+            let gap_end = first_partition_lba(f, sector_size)?.unwrap_or(u64::MAX);
+            let slots = loader::embed_lbas(sector_size);
+            let needed = slots[1]
+                + (loader::LOADER_STAGE2.len() as u64 + sector_size as u64 - 1)
+                    / sector_size as u64;
+            if needed > gap_end {
+                anyhow::bail!(
+                    "the gap before the first partition ends at sector {}, but the \
+                     old and new stage2 need sectors up to {} to upgrade safely",
+                    gap_end,
+                    needed
+                );
+            }
+            let start = if current == slots[0] {
+                slots[1]
+            } else {
+                slots[0]
+            };
+            Some(loader::embed_stage2(f, sector_size, start, gap_end)?)
+        }
+        None => None,
+    };
+
+    let blocklist = {
+        let fs = loader::open_fs(f, fs_start, fs_end)?;
+        let blocklist = match embedded_blocklist {
+            Some(blocklist) => blocklist,
+            None => loader::copy_stage2(&fs, fs_start, sector_size, STAGE2_TEMP_NAME)?,
+        };
+        loader::copy_stage3(&fs, STAGE3_TEMP_NAME)?;
+
+        swap_in(&fs, STAGE3_TEMP_NAME, loader::LOADER_STAGE3_NAME)?;
+        if embedded.is_none() {
+            swap_in(&fs, STAGE2_TEMP_NAME, loader::LOADER_STAGE2_NAME)?;
+        }
+        blocklist
+    };
+
+    // everything stage1 will point at has to be on disk before it does
+    f.flush().context("could not flush image")?;
+    file.sync_all().context("could not sync image")?;
+    loader::write_stage1(f, &blocklist, sector_size)?;
+
+    // keep uninstall from mistaking the new stage1 for someone else's
+    if let Some(mut backup) = loader::read_backup(f, sector_size)? {
+        backup.installed_crc = crc32fast::hash(&loader::read_boot_code(f)?);
+        loader::write_backup(f, sector_size, &backup)?;
+    }
+    f.flush().context("could not flush image")?;
+
+    Ok(())
+}
+
+/// upgrade the loader in an existing image, leaving user files, the
+/// partition table, and the boot code backup alone
+pub fn upgrade(path: &Path, partition: Option<usize>) -> anyhow::Result<()> {
+    let f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("could not open {}", path.display()))?;
+    // a second handle on the same file, to sync through
+    let file = f
+        .try_clone()
+        .with_context(|| format!("could not open {}", path.display()))?;
+    let mut f = fscommon::BufStream::new(f);
+
+    let sector_size = loader::detect_sector_size(&mut f)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let (fs_start, fs_end) = find_partition(&mut f, partition, sector_size)
+        .with_context(|| format!("could not find a partition in {}", path.display()))?;
+    // stage2 stays in the filesystem or embedded, whichever it was
+    let blocklist = loader::read_blocklist(&mut f)?;
+    let embedded = if loader::is_embedded(&blocklist, sector_size) {
+        blocklist.first().map(|&(start, _)| start as u64)
+    } else {
+        None
+    };
+    upgrade_into(&mut f, &file, fs_start, fs_end, sector_size, embedded)
+        .with_context(|| format!("could not upgrade loader in {}", path.display()))
+}
+
+/// put back the boot code the loader replaced, byte for byte
+///
+/// this refuses if the boot code has changed since the loader was
+/// installed, unless forced. the loader files are left where they are.
+pub fn uninstall(path: &Path, force: bool) -> anyhow::Result<()> {
+    let f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("could not open {}", path.display()))?;
+    let mut f = fscommon::BufStream::new(f);
+
+    let sector_size = loader::detect_sector_size(&mut f)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let backup = loader::read_backup(&mut f, sector_size)
+        .with_context(|| format!("could not read {}", path.display()))?
+        .ok_or_else(|| {
+            anyhow::anyhow!("no backup of the original boot code in {}", path.display())
+        })?;
+
+    let current = loader::read_boot_code(&mut f)?;
+    if crc32fast::hash(&current) != backup.installed_crc && !force {
+        anyhow::bail!(
+            "the boot code in {} has changed since the loader was installed, \
+             use --force to restore it anyway",
+            path.display()
+        );
+    }
+
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(&backup.boot_code)
+        .context("could not restore boot code")?;
+    loader::clear_backup(&mut f, sector_size)?;
+    f.flush()
+        .with_context(|| format!("could not write {}", path.display()))
+}
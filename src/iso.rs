@@ -0,0 +1,637 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::Context;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use crate::loader::{self, Dir};
+
+const SECTOR: u64 = 2048;
+
+// the first 16 sectors are the system area, where the MBR lives
+const PRIMARY_LBA: u32 = 16;
+const BOOT_RECORD_LBA: u32 = 17;
+const JOLIET_LBA: u32 = 18;
+const TERMINATOR_LBA: u32 = 19;
+const CATALOG_LBA: u32 = 20;
+
+// Joliet allows at most 64 UCS-2 characters in a name
+const JOLIET_NAME_MAX: usize = 64;
+
+/// a file or directory copied out of the FAT filesystem
+struct Node {
+    /// file identifier in the primary volume
+    short: Vec<u8>,
+    /// file identifier in the Joliet volume
+    long: Vec<u8>,
+    modified: fatfs::DateTime,
+    kind: Kind,
+}
+
+enum Kind {
+    File { lba: u32, size: u32 },
+    Dir(Vec<Node>),
+}
+
+/// one directory in a volume, in path table order
+struct Directory<'a> {
+    /// index of the parent, which is itself for the root
+    parent: usize,
+    name: Vec<u8>,
+    modified: fatfs::DateTime,
+    entries: Vec<Entry<'a>>,
+    lba: u32,
+    size: u32,
+}
+
+struct Entry<'a> {
+    name: &'a [u8],
+    modified: fatfs::DateTime,
+    target: Target,
+}
+
+enum Target {
+    File { lba: u32, size: u32 },
+    Dir(usize),
+}
+
+fn sectors(bytes: u64) -> u32 {
+    ((bytes + SECTOR - 1) / SECTOR) as u32
+}
+
+fn both_u16(buf: &mut [u8], value: u16) {
+    LittleEndian::write_u16(&mut buf[0..2], value);
+    BigEndian::write_u16(&mut buf[2..4], value);
+}
+
+fn both_u32(buf: &mut [u8], value: u32) {
+    LittleEndian::write_u32(&mut buf[0..4], value);
+    BigEndian::write_u32(&mut buf[4..8], value);
+}
+
+fn ucs2(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(|c| c.to_be_bytes()).collect()
+}
+
+/// fill a text field, padded with spaces
+fn text(field: &mut [u8], value: &str, joliet: bool) {
+    let value = if joliet {
+        ucs2(value)
+    } else {
+        value.to_ascii_uppercase().into_bytes()
+    };
+    let space: &[u8] = if joliet { &[0, b' '] } else { b" " };
+    for (i, b) in field.iter_mut().enumerate() {
+        *b = match value.get(i) {
+            Some(&c) => c,
+            None => space[i % space.len()],
+        };
+    }
+}
+
+/// the 7 byte timestamp used in directory records, in UTC
+fn record_time(t: &fatfs::DateTime) -> [u8; 7] {
+    [
+        (t.date.year - 1900) as u8,
+        t.date.month as u8,
+        t.date.day as u8,
+        t.time.hour as u8,
+        t.time.min as u8,
+        t.time.sec as u8,
+        0,
+    ]
+}
+
+/// the 17 byte timestamp used in volume descriptors, in UTC
+fn volume_time(t: &fatfs::DateTime) -> [u8; 17] {
+    let mut buf = [0; 17];
+    let digits = format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}00",
+        t.date.year, t.date.month, t.date.day, t.time.hour, t.time.min, t.time.sec
+    );
+    buf[..16].copy_from_slice(digits.as_bytes());
+    buf
+}
+
+/// append a directory record, moving on to the next sector if it
+/// would not fit in this one
+fn push_record(
+    buf: &mut Vec<u8>,
+    name: &[u8],
+    modified: &fatfs::DateTime,
+    lba: u32,
+    size: u32,
+    dir: bool,
+) {
+    let len = 33 + name.len() + (name.len() + 1) % 2;
+    if buf.len() % SECTOR as usize + len > SECTOR as usize {
+        buf.resize((buf.len() / SECTOR as usize + 1) * SECTOR as usize, 0);
+    }
+
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    let record = &mut buf[start..];
+    record[0] = len as u8;
+    both_u32(&mut record[2..10], lba);
+    both_u32(&mut record[10..18], size);
+    record[18..25].copy_from_slice(&record_time(modified));
+    record[25] = if dir { 2 } else { 0 };
+    both_u16(&mut record[28..32], 1);
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+}
+
+/// the d-character for c: upper case letters, digits and underscore are
+/// all a level 1 name may hold, so anything else becomes an underscore
+fn d_char(c: u8) -> u8 {
+    match c.to_ascii_uppercase() {
+        c @ (b'A'..=b'Z' | b'0'..=b'9' | b'_') => c,
+        _ => b'_',
+    }
+}
+
+/// the level 1 identifier for a FAT short name, NAME.EXT;1 for files
+/// (with the dot even when there is no extension) and NAME for
+/// directories, which can't have one
+///
+/// mapping to d-characters can make two names the same, so the base
+/// name gets a number on the end until it is not in used
+fn level1_name(short: &str, dir: bool, used: &mut HashSet<Vec<u8>>) -> Vec<u8> {
+    let (base, ext) = short.split_once('.').unwrap_or((short, ""));
+    let base: Vec<u8> = base.bytes().map(d_char).collect();
+    let ext: Vec<u8> = ext.bytes().map(d_char).collect();
+
+    let mut n = 0;
+    loop {
+        let mut name = base.clone();
+        if n > 0 {
+            let suffix = n.to_string();
+            name.truncate(8 - suffix.len());
+            name.extend_from_slice(suffix.as_bytes());
+        }
+        if !dir {
+            name.push(b'.');
+            name.extend_from_slice(&ext);
+            name.extend_from_slice(b";1");
+        }
+        if used.insert(name.clone()) {
+            return name;
+        }
+        n += 1;
+    }
+}
+
+/// copy every file under dir to the end of out, starting at sector
+/// next, and describe them
+fn copy_tree<W: Write + Seek>(dir: &Dir, out: &mut W, next: &mut u32) -> anyhow::Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    let mut used = HashSet::new();
+    for entry in dir.iter() {
+        let entry = entry.context("could not read directory")?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        if name.encode_utf16().count() > JOLIET_NAME_MAX {
+            anyhow::bail!("{:?} is too long for a Joliet name", name);
+        }
+
+        // FAT short names are already upper case 8.3, which is close
+        // to what level 1 wants
+        let short = level1_name(&entry.short_file_name(), entry.is_dir(), &mut used);
+        let kind = if entry.is_dir() {
+            Kind::Dir(copy_tree(&entry.to_dir(), out, next)?)
+        } else {
+            let size = entry.len();
+            if size == 0 {
+                Kind::File { lba: 0, size: 0 }
+            } else {
+                let lba = *next;
+                out.seek(SeekFrom::Start(lba as u64 * SECTOR))?;
+                std::io::copy(&mut entry.to_file(), out)
+                    .with_context(|| format!("could not copy {}", name))?;
+                *next += sectors(size);
+                Kind::File {
+                    lba,
+                    size: size as u32,
+                }
+            }
+        };
+
+        nodes.push(Node {
+            short,
+            long: ucs2(&name),
+            modified: entry.modified(),
+            kind,
+        });
+    }
+    Ok(nodes)
+}
+
+/// lay out the tree as a list of directories, breadth first and sorted
+/// by name, which is the order the path tables need
+fn flatten<'a>(
+    root: &'a [Node],
+    modified: fatfs::DateTime,
+    name: fn(&Node) -> &[u8],
+) -> Vec<Directory<'a>> {
+    let mut pending = vec![root];
+    let mut dirs = vec![Directory {
+        parent: 0,
+        name: vec![0],
+        modified,
+        entries: Vec::new(),
+        lba: 0,
+        size: 0,
+    }];
+
+    let mut i = 0;
+    while i < dirs.len() {
+        let level: &'a [Node] = pending[i];
+        let mut nodes: Vec<&Node> = level.iter().collect();
+        nodes.sort_by(|a, b| name(a).cmp(name(b)));
+
+        for node in nodes {
+            let target = match node.kind {
+                Kind::File { lba, size } => Target::File { lba, size },
+                Kind::Dir(ref children) => {
+                    pending.push(children);
+                    dirs.push(Directory {
+                        parent: i,
+                        name: name(node).to_vec(),
+                        modified: node.modified,
+                        entries: Vec::new(),
+                        lba: 0,
+                        size: 0,
+                    });
+                    Target::Dir(dirs.len() - 1)
+                }
+            };
+            dirs[i].entries.push(Entry {
+                name: name(node),
+                modified: node.modified,
+                target,
+            });
+        }
+        i += 1;
+    }
+
+    dirs
+}
+
+/// the contents of directory i, padded out to whole sectors
+fn directory(dirs: &[Directory], i: usize) -> Vec<u8> {
+    let dir = &dirs[i];
+    let parent = &dirs[dir.parent];
+
+    let mut buf = Vec::new();
+    push_record(&mut buf, &[0], &dir.modified, dir.lba, dir.size, true);
+    push_record(
+        &mut buf,
+        &[1],
+        &parent.modified,
+        parent.lba,
+        parent.size,
+        true,
+    );
+    for entry in dir.entries.iter() {
+        let (lba, size, is_dir) = match entry.target {
+            Target::File { lba, size } => (lba, size, false),
+            Target::Dir(j) => (dirs[j].lba, dirs[j].size, true),
+        };
+        push_record(&mut buf, entry.name, &entry.modified, lba, size, is_dir);
+    }
+    buf.resize(sectors(buf.len() as u64) as usize * SECTOR as usize, 0);
+    buf
+}
+
+/// give each directory its size, and a place starting at sector next
+fn place(dirs: &mut [Directory], next: &mut u32) {
+    for i in 0..dirs.len() {
+        dirs[i].size = directory(dirs, i).len() as u32;
+        dirs[i].lba = *next;
+        *next += sectors(dirs[i].size as u64);
+    }
+}
+
+fn path_table(dirs: &[Directory], big_endian: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for dir in dirs {
+        let start = buf.len();
+        buf.resize(start + 8 + dir.name.len() + dir.name.len() % 2, 0);
+        let record = &mut buf[start..];
+        record[0] = dir.name.len() as u8;
+        if big_endian {
+            BigEndian::write_u32(&mut record[2..6], dir.lba);
+            BigEndian::write_u16(&mut record[6..8], dir.parent as u16 + 1);
+        } else {
+            LittleEndian::write_u32(&mut record[2..6], dir.lba);
+            LittleEndian::write_u16(&mut record[6..8], dir.parent as u16 + 1);
+        }
+        record[8..8 + dir.name.len()].copy_from_slice(&dir.name);
+    }
+    buf
+}
+
+/// one volume: its directories, and where its path tables go
+struct Volume<'a> {
+    dirs: Vec<Directory<'a>>,
+    path_table_size: u32,
+    l_table: u32,
+    m_table: u32,
+}
+
+impl<'a> Volume<'a> {
+    fn new(dirs: Vec<Directory<'a>>, next: &mut u32) -> Self {
+        let path_table_size = path_table(&dirs, false).len() as u32;
+        let l_table = *next;
+        let m_table = l_table + sectors(path_table_size as u64);
+        *next = m_table + sectors(path_table_size as u64);
+        Self {
+            dirs,
+            path_table_size,
+            l_table,
+            m_table,
+        }
+    }
+
+    /// the primary or Joliet supplementary volume descriptor
+    fn descriptor(
+        &self,
+        joliet: bool,
+        volume_id: &str,
+        volume_sectors: u32,
+        time: &fatfs::DateTime,
+    ) -> Vec<u8> {
+        let mut d = vec![0; SECTOR as usize];
+        d[0] = if joliet { 2 } else { 1 };
+        d[1..6].copy_from_slice(b"CD001");
+        d[6] = 1;
+        text(&mut d[8..40], "", joliet);
+        text(&mut d[40..72], volume_id, joliet);
+        both_u32(&mut d[80..88], volume_sectors);
+        if joliet {
+            // UCS-2 level 3
+            d[88..91].copy_from_slice(b"%/E");
+        }
+        both_u16(&mut d[120..124], 1);
+        both_u16(&mut d[124..128], 1);
+        both_u16(&mut d[128..132], SECTOR as u16);
+        both_u32(&mut d[132..140], self.path_table_size);
+        LittleEndian::write_u32(&mut d[140..144], self.l_table);
+        BigEndian::write_u32(&mut d[148..152], self.m_table);
+
+        let root = &self.dirs[0];
+        let mut record = Vec::new();
+        push_record(&mut record, &[0], time, root.lba, root.size, true);
+        d[156..190].copy_from_slice(&record);
+
+        // volume set, publisher, data preparer, and application
+        text(&mut d[190..318], "", joliet);
+        text(&mut d[318..446], "", joliet);
+        text(&mut d[446..574], "", joliet);
+        text(&mut d[574..702], "BLUE-TOOL", joliet);
+        // copyright, abstract, and bibliographic files
+        text(&mut d[702..813], "", joliet);
+
+        let time = volume_time(time);
+        d[813..830].copy_from_slice(&time);
+        d[830..847].copy_from_slice(&time);
+        d[847..863].copy_from_slice(b"0000000000000000");
+        d[864..880].copy_from_slice(b"0000000000000000");
+        d[881] = 1;
+        d
+    }
+
+    fn write<W: Write + Seek>(&self, out: &mut W) -> anyhow::Result<()> {
+        out.seek(SeekFrom::Start(self.l_table as u64 * SECTOR))?;
+        out.write_all(&path_table(&self.dirs, false))?;
+        out.seek(SeekFrom::Start(self.m_table as u64 * SECTOR))?;
+        out.write_all(&path_table(&self.dirs, true))?;
+        for (i, dir) in self.dirs.iter().enumerate() {
+            out.seek(SeekFrom::Start(dir.lba as u64 * SECTOR))?;
+            out.write_all(&directory(&self.dirs, i))?;
+        }
+        Ok(())
+    }
+}
+
+/// the El Torito boot record, pointing at the boot catalog
+fn boot_record() -> Vec<u8> {
+    let mut d = vec![0; SECTOR as usize];
+    d[1..6].copy_from_slice(b"CD001");
+    d[6] = 1;
+    d[7..30].copy_from_slice(b"EL TORITO SPECIFICATION");
+    LittleEndian::write_u32(&mut d[71..75], CATALOG_LBA);
+    d
+}
+
+/// the El Torito boot catalog, with a single no emulation entry
+fn boot_catalog(image_lba: u32, image_sectors: u16) -> Vec<u8> {
+    let mut c = vec![0; SECTOR as usize];
+
+    // validation entry, for x86, checksummed so the words sum to 0
+    c[0] = 1;
+    c[4..8].copy_from_slice(b"blue");
+    c[30] = 0x55;
+    c[31] = 0xaa;
+    let sum = c[..32]
+        .chunks(2)
+        .fold(0u16, |sum, w| sum.wrapping_add(LittleEndian::read_u16(w)));
+    LittleEndian::write_u16(&mut c[28..30], 0u16.wrapping_sub(sum));
+
+    // default entry: bootable, no emulation, and a load segment of 0,
+    // which means the usual 0x07c0. the count is in 512 byte sectors.
+    c[32] = 0x88;
+    LittleEndian::write_u16(&mut c[38..40], image_sectors);
+    LittleEndian::write_u32(&mut c[40..44], image_lba);
+    c
+}
+
+/// stage1 with stage2 right behind it, as it would be in memory
+///
+/// the BIOS loads all of it at 0x7c00, so stage1 gets an empty
+/// blocklist and goes straight on to stage2
+fn boot_image() -> anyhow::Result<Vec<u8>> {
+    let mut image = vec![0; blue_layout::STAGE2_ENTRY as usize];
+    loader::write_stage1(&mut Cursor::new(&mut image[..]), &[], SECTOR as u16)?;
+    image.extend_from_slice(loader::LOADER_STAGE2);
+    Ok(image)
+}
+
+/// turn the raw MBR disk image at path into a hybrid ISO9660 image,
+/// which also boots from CD through El Torito
+///
+/// the partitions are left alone, so the image still boots as a disk.
+/// the volume descriptors, path tables and directories go in the gap
+/// between the MBR and the first partition, at gap_end, while the boot
+/// image and a copy of every file in the FAT filesystem between fs_start
+/// and fs_end are added past the end of the disk, at disk_size.
+pub fn hybridize(
+    path: &Path,
+    fs_start: u64,
+    fs_end: u64,
+    gap_end: u64,
+    disk_size: u64,
+    volume_id: &str,
+    time: i64,
+) -> anyhow::Result<()> {
+    let src =
+        std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut src = fscommon::BufStream::new(src);
+    let out = std::fs::File::options()
+        .write(true)
+        .open(path)
+        .with_context(|| format!("could not open {}", path.display()))?;
+    let mut out = std::io::BufWriter::new(out);
+
+    let mut next = sectors(disk_size);
+
+    let boot = boot_image()?;
+    let boot_lba = next;
+    let boot_sectors =
+        u16::try_from((boot.len() + 511) / 512).context("stage2 is too large to boot from CD")?;
+    out.seek(SeekFrom::Start(boot_lba as u64 * SECTOR))?;
+    out.write_all(&boot)?;
+    next += sectors(boot.len() as u64);
+
+    let root = {
+        let fs = loader::open_fs(&mut src, fs_start, fs_end)?;
+        copy_tree(&fs.root_dir(), &mut out, &mut next).context("could not copy files")?
+    };
+    let volume_sectors = next;
+
+    let time = crate::files::fat_datetime(time);
+    let mut meta = CATALOG_LBA + 1;
+    let mut primary = Volume::new(flatten(&root, time, |n| &n.short), &mut meta);
+    let mut joliet = Volume::new(flatten(&root, time, |n| &n.long), &mut meta);
+    place(&mut primary.dirs, &mut meta);
+    place(&mut joliet.dirs, &mut meta);
+
+    let needed = meta as u64 * SECTOR;
+    if needed > gap_end {
+        anyhow::bail!(
+            "the ISO9660 directories need the first {} bytes, but the first partition \
+             starts at {}; try a larger alignment",
+            needed,
+            gap_end
+        );
+    }
+
+    let descriptors = [
+        (
+            PRIMARY_LBA,
+            primary.descriptor(false, volume_id, volume_sectors, &time),
+        ),
+        (BOOT_RECORD_LBA, boot_record()),
+        (
+            JOLIET_LBA,
+            joliet.descriptor(true, volume_id, volume_sectors, &time),
+        ),
+        (TERMINATOR_LBA, {
+            let mut d = vec![0; SECTOR as usize];
+            d[0] = 255;
+            d[1..6].copy_from_slice(b"CD001");
+            d[6] = 1;
+            d
+        }),
+        (CATALOG_LBA, boot_catalog(boot_lba, boot_sectors)),
+    ];
+    for (lba, data) in descriptors.iter() {
+        out.seek(SeekFrom::Start(*lba as u64 * SECTOR))?;
+        out.write_all(data)?;
+    }
+    primary.write(&mut out)?;
+    joliet.write(&mut out)?;
+
+    let out = out
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .with_context(|| format!("could not write {}", path.display()))?;
+    out.set_len(volume_sectors as u64 * SECTOR)
+        .with_context(|| format!("could not resize {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level1(names: &[(&str, bool)]) -> Vec<String> {
+        let mut used = HashSet::new();
+        names
+            .iter()
+            .map(|&(short, dir)| String::from_utf8(level1_name(short, dir, &mut used)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn level1_names() {
+        assert_eq!(
+            level1(&[("HELLO.TXT", false), ("README", false), ("DOCS", true)]),
+            ["HELLO.TXT;1", "README.;1", "DOCS"]
+        );
+    }
+
+    #[test]
+    fn level1_names_are_d_characters() {
+        assert_eq!(
+            level1(&[("BLUE-L~1.BIN", false), ("a b$c.t+t", false), ("X.Y", true)]),
+            ["BLUE_L_1.BIN;1", "A_B_C.T_T;1", "X"]
+        );
+    }
+
+    #[test]
+    fn level1_names_are_unique() {
+        assert_eq!(
+            level1(&[
+                ("A~1.TXT", false),
+                ("A_1.TXT", false),
+                ("A+1.TXT", false),
+                ("A_1.DOC", false),
+                ("LONG_NAM", true),
+                ("LONG-NAM", true),
+                ("LONGNAME.TXT", false),
+            ]),
+            [
+                "A_1.TXT;1",
+                "A_11.TXT;1",
+                "A_12.TXT;1",
+                "A_1.DOC;1",
+                "LONG_NAM",
+                "LONG_NA1",
+                "LONGNAME.TXT;1",
+            ]
+        );
+    }
+
+    #[test]
+    fn joliet_names_are_ucs2_big_endian() {
+        assert_eq!(ucs2("a.txt"), b"\0a\0.\0t\0x\0t");
+        assert_eq!(ucs2("\u{e9}\u{1f600}"), b"\0\xe9\xd8\x3d\xde\x00");
+    }
+
+    #[test]
+    fn boot_catalog_entries() {
+        let c = boot_catalog(1234, 56);
+        assert_eq!(c.len(), SECTOR as usize);
+
+        // the validation entry's words sum to zero
+        assert_eq!(c[0], 1, "header id");
+        assert_eq!(c[1], 0, "x86 platform");
+        assert_eq!(&c[30..32], b"\x55\xaa");
+        let sum = c[..32]
+            .chunks(2)
+            .fold(0u16, |sum, w| sum.wrapping_add(LittleEndian::read_u16(w)));
+        assert_eq!(sum, 0);
+
+        assert_eq!(c[32], 0x88, "bootable");
+        assert_eq!(c[33], 0, "no emulation");
+        assert_eq!(LittleEndian::read_u16(&c[34..36]), 0, "load segment");
+        assert_eq!(LittleEndian::read_u16(&c[38..40]), 56);
+        assert_eq!(LittleEndian::read_u32(&c[40..44]), 1234);
+    }
+}
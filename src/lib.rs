@@ -0,0 +1,15 @@
+//! Build, inspect, and install disk images for the blue loader.
+//!
+//! [`image::ImageBuilder`] is the place to start for building images
+//! from other tools.
+
+pub mod files;
+pub mod format;
+pub mod gpt;
+pub mod image;
+pub mod inspect;
+pub mod install;
+pub mod iso;
+pub mod loader;
+pub mod manifest;
+pub mod verify;
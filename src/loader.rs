@@ -0,0 +1,490 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::Context;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use fatfs::TimeProvider;
+
+// where blue-tool fills in stage1, from layout.ld
+pub const LOADER_STAGE1_SECTOR_SIZE: u64 = blue_layout::STAGE1_SECTOR_SIZE as u64;
+pub const LOADER_STAGE1_BLOCKLIST: u64 = blue_layout::STAGE1_BLOCKLIST as u64;
+pub const LOADER_STAGE1_BLOCKLIST_ENTRIES: usize = blue_layout::STAGE1_BLOCKLIST_ENTRIES;
+
+pub const LOADER_STAGE1: &[u8] = include_bytes!(env!("BLUE_LOADER_STAGE1"));
+
+// stage1 must fit before the disk signature and partition table
+static_assertions::const_assert!(LOADER_STAGE1.len() <= blue_layout::STAGE1_END as usize);
+
+pub const LOADER_STAGE2: &[u8] = include_bytes!(env!("BLUE_LOADER_STAGE2"));
+pub const LOADER_STAGE3: &[u8] = include_bytes!(env!("BLUE_LOADER_STAGE3"));
+
+pub const LOADER_STAGE2_NAME: &str = "blue-loader-stage2.bin";
+pub const LOADER_STAGE3_NAME: &str = "blue-loader-stage3.bin";
+
+pub use blue_layout::{BACKUP_MAGIC, BOOT_CODE_SIZE};
+
+pub const DEFAULT_SECTOR_SIZE: u16 = 512;
+// logical sector sizes we can build images for
+pub const SECTOR_SIZES: &[u16] = &[512, 4096];
+
+pub type Image = fscommon::BufStream<std::fs::File>;
+pub type FileSystem<'a> = fatfs::FileSystem<fscommon::StreamSlice<&'a mut Image>, Clock>;
+pub type Dir<'a, 'b> = fatfs::Dir<'a, fscommon::StreamSlice<&'b mut Image>, Clock>;
+pub type File<'a, 'b> = fatfs::File<'a, fscommon::StreamSlice<&'b mut Image>, Clock>;
+
+/// where fatfs gets timestamps for the files it creates and changes
+///
+/// by default this is the host clock, but it can be pinned to a fixed
+/// time so that builds are reproducible
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Clock {
+    fixed: Option<fatfs::DateTime>,
+}
+
+impl Clock {
+    /// a clock stuck at secs since the unix epoch
+    pub fn fixed(secs: i64) -> Self {
+        Self {
+            fixed: Some(crate::files::fat_datetime(secs)),
+        }
+    }
+
+    pub fn is_fixed(&self) -> bool {
+        self.fixed.is_some()
+    }
+}
+
+impl TimeProvider for Clock {
+    fn get_current_date(&self) -> fatfs::Date {
+        self.get_current_date_time().date
+    }
+
+    fn get_current_date_time(&self) -> fatfs::DateTime {
+        match self.fixed {
+            Some(time) => time,
+            None => fatfs::DefaultTimeProvider::new().get_current_date_time(),
+        }
+    }
+}
+
+/// open the FAT filesystem living between byte offsets start and end
+pub fn open_fs(f: &mut Image, start: u64, end: u64) -> anyhow::Result<FileSystem> {
+    open_fs_with(f, start, end, Clock::default())
+}
+
+/// open a FAT filesystem, stamping any changes with the given clock
+pub fn open_fs_with(
+    f: &mut Image,
+    start: u64,
+    end: u64,
+    clock: Clock,
+) -> anyhow::Result<FileSystem> {
+    let fatimg = fscommon::StreamSlice::new(f, start, end)?;
+    let options = fatfs::FsOptions::new().time_provider(clock);
+    fatfs::FileSystem::new(fatimg, options).context("could not open FAT filesystem")
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlocklistError {
+    #[error("stage2 is in {needed} pieces, but stage1 only has room for {available}")]
+    TooManyExtents { needed: usize, available: usize },
+    #[error("stage2 sector {sector} is past the 32-bit range stage1 can address")]
+    SectorOutOfRange { sector: u64 },
+    #[error("stage2 extent at byte {offset} is not sector aligned")]
+    Unaligned { offset: u64 },
+}
+
+/// find the extents (absolute byte offset, byte length) of a file,
+/// merging any that are adjacent
+pub fn file_extents(file: &mut File, fs_start: u64) -> anyhow::Result<Vec<(u64, u32)>> {
+    let mut extents: Vec<(u64, u32)> = Vec::new();
+    for extent in file.extents() {
+        let extent = extent.context("could not read file extents")?;
+        let start = fs_start + extent.offset;
+        let size = extent.size;
+
+        if let Some(last) = extents.last_mut() {
+            let last_end = last.0 + last.1 as u64;
+            if start == last_end {
+                last.1 += size;
+            } else {
+                extents.push((start, size));
+            }
+        } else {
+            extents.push((start, size));
+        }
+    }
+
+    Ok(extents)
+}
+
+/// turn extents into the (sector, count) entries stage1 understands
+pub fn encode_blocklist(
+    extents: &[(u64, u32)],
+    sector_size: u16,
+) -> Result<Vec<(u32, u32)>, BlocklistError> {
+    if extents.len() > LOADER_STAGE1_BLOCKLIST_ENTRIES {
+        return Err(BlocklistError::TooManyExtents {
+            needed: extents.len(),
+            available: LOADER_STAGE1_BLOCKLIST_ENTRIES,
+        });
+    }
+
+    extents
+        .iter()
+        .map(|&(start, size)| {
+            let sector_size = sector_size as u64;
+            if start % sector_size != 0 {
+                return Err(BlocklistError::Unaligned { offset: start });
+            }
+            let start_sec = start / sector_size;
+            let size_sec = (size as u64 + sector_size - 1) / sector_size;
+            let last_sec = start_sec + size_sec - 1;
+            if last_sec > u32::MAX as u64 {
+                return Err(BlocklistError::SectorOutOfRange { sector: last_sec });
+            }
+            Ok((start_sec as u32, size_sec as u32))
+        })
+        .collect()
+}
+
+// how many times to try placing stage2 before giving up
+const STAGE2_ATTEMPTS: usize = 8;
+
+/// write stage2 and stage3 into the root of fs, and return the
+/// stage1 blocklist for stage2
+pub fn copy_stages(
+    fs: &FileSystem,
+    fs_start: u64,
+    sector_size: u16,
+) -> anyhow::Result<Vec<(u32, u32)>> {
+    let blocklist = copy_stage2(fs, fs_start, sector_size, LOADER_STAGE2_NAME)?;
+    copy_stage3(fs, LOADER_STAGE3_NAME)?;
+    Ok(blocklist)
+}
+
+/// write stage2 into the root of fs under name, and return the stage1
+/// blocklist for it
+///
+/// if stage2 lands in too many pieces, the pieces are held on to so
+/// the next try has to go somewhere else, usually the contiguous free
+/// space at the end of the filesystem.
+pub fn copy_stage2(
+    fs: &FileSystem,
+    fs_start: u64,
+    sector_size: u16,
+    name: &str,
+) -> anyhow::Result<Vec<(u32, u32)>> {
+    let root = fs.root_dir();
+
+    // the holds go whether or not stage2 found a place
+    let mut holds = Vec::new();
+    let result = place_stage2(fs, fs_start, sector_size, name, &mut holds);
+    let mut cleanup = Ok(());
+    for hold in holds {
+        if let Err(e) = root.remove(&hold) {
+            if cleanup.is_ok() {
+                cleanup = Err(e).with_context(|| format!("could not remove {}", hold));
+            }
+        }
+    }
+    let blocklist = result?;
+    cleanup?;
+    Ok(blocklist)
+}
+
+/// write stage2 until it lands in few enough pieces, moving each
+/// try that doesn't aside under a name added to holds
+fn place_stage2(
+    fs: &FileSystem,
+    fs_start: u64,
+    sector_size: u16,
+    name: &str,
+    holds: &mut Vec<String>,
+) -> anyhow::Result<Vec<(u32, u32)>> {
+    let root = fs.root_dir();
+    loop {
+        let mut stage2 = root
+            .create_file(name)
+            .with_context(|| format!("could not create {}", name))?;
+        stage2.truncate()?;
+        stage2
+            .write_all(LOADER_STAGE2)
+            .with_context(|| format!("could not write {}", name))?;
+        let extents = file_extents(&mut stage2, fs_start)
+            .with_context(|| format!("could not locate {}", name))?;
+        drop(stage2);
+
+        match encode_blocklist(&extents, sector_size) {
+            Ok(blocklist) => return Ok(blocklist),
+            Err(BlocklistError::TooManyExtents { .. }) if holds.len() < STAGE2_ATTEMPTS => {
+                let hold = format!("blue-loader-stage2.hold{}", holds.len());
+                root.rename(name, &root, &hold)
+                    .context("could not move fragmented stage2 aside")?;
+                holds.push(hold);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// write stage3 into the root of fs under name
+pub fn copy_stage3(fs: &FileSystem, name: &str) -> anyhow::Result<()> {
+    let mut stage3 = fs
+        .root_dir()
+        .create_file(name)
+        .with_context(|| format!("could not create {}", name))?;
+    stage3.truncate()?;
+    stage3
+        .write_all(LOADER_STAGE3)
+        .with_context(|| format!("could not write {}", name))?;
+    Ok(())
+}
+
+/// the most sectors an embedded stage2 can ever take: as much as the
+/// build lets it grow, so a new one never runs into an old one
+fn embed_slot_sectors(sector_size: u16) -> u64 {
+    let max = (blue_layout::STACK_TOP - blue_layout::STACK_SIZE - blue_layout::STAGE2_ENTRY) as u64;
+    (max + sector_size as u64 - 1) / sector_size as u64
+}
+
+/// where stage2 goes when embedded in the gap before the first
+/// partition, just after the backup sector. upgrades alternate between
+/// the two, so the stage2 stage1 loads is never written over.
+pub fn embed_lbas(sector_size: u16) -> [u64; 2] {
+    let first = backup_lba(sector_size) + 1;
+    [first, first + embed_slot_sectors(sector_size)]
+}
+
+/// does this stage1 blocklist point at an embedded stage2?
+pub fn is_embedded(blocklist: &[(u32, u32)], sector_size: u16) -> bool {
+    blocklist.first().map_or(false, |&(start, _)| {
+        embed_lbas(sector_size).contains(&(start as u64))
+    })
+}
+
+/// write stage2 into the gap at start, one of embed_lbas, and return
+/// the stage1 blocklist for it, a single run of sectors
+///
+/// nothing is written unless all of stage2 fits before gap_end, the
+/// first sector that belongs to a partition
+pub fn embed_stage2<W: Write + Seek>(
+    f: &mut W,
+    sector_size: u16,
+    start: u64,
+    gap_end: u64,
+) -> anyhow::Result<Vec<(u32, u32)>> {
+    let size = sector_size as u64;
+    let sectors = (LOADER_STAGE2.len() as u64 + size - 1) / size;
+    if start + sectors > gap_end {
+        anyhow::bail!(
+            "stage2 needs sectors {}..{} to be embedded, but the first partition starts at {}",
+            start,
+            start + sectors,
+            gap_end
+        );
+    }
+
+    let mut data = LOADER_STAGE2.to_vec();
+    data.resize((sectors * size) as usize, 0);
+    f.seek(SeekFrom::Start(start * size))?;
+    f.write_all(&data)
+        .context("could not write embedded stage2")?;
+
+    Ok(encode_blocklist(
+        &[(start * size, LOADER_STAGE2.len() as u32)],
+        sector_size,
+    )?)
+}
+
+/// write stage1 into the boot code area, followed by the sector size
+/// and stage2 blocklist
+///
+/// this only touches the first 440 bytes, so the disk signature and
+/// partition table are left alone
+pub fn write_stage1<W: Write + Seek>(
+    f: &mut W,
+    blocklist: &[(u32, u32)],
+    sector_size: u16,
+) -> anyhow::Result<()> {
+    if blocklist.len() > LOADER_STAGE1_BLOCKLIST_ENTRIES {
+        return Err(BlocklistError::TooManyExtents {
+            needed: blocklist.len(),
+            available: LOADER_STAGE1_BLOCKLIST_ENTRIES,
+        }
+        .into());
+    }
+
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(LOADER_STAGE1)
+        .context("could not write stage1")?;
+
+    // stage1 needs this to know how far each read moves along
+    f.seek(SeekFrom::Start(LOADER_STAGE1_SECTOR_SIZE))?;
+    f.write_u16::<LittleEndian>(sector_size)?;
+
+    // write blocklist to stage1
+    f.seek(SeekFrom::Start(LOADER_STAGE1_BLOCKLIST))?;
+    for &(start, count) in blocklist.iter() {
+        f.write_u32::<LittleEndian>(start)?;
+        f.write_u32::<LittleEndian>(count)?;
+    }
+
+    // clear out any unused entries
+    for _ in blocklist.len()..LOADER_STAGE1_BLOCKLIST_ENTRIES {
+        f.write_u32::<LittleEndian>(0)?;
+        f.write_u32::<LittleEndian>(0)?;
+    }
+
+    Ok(())
+}
+
+/// read the stage2 blocklist back out of stage1, as (sector, count) pairs
+pub fn read_blocklist<R: Read + Seek>(f: &mut R) -> anyhow::Result<Vec<(u32, u32)>> {
+    f.seek(SeekFrom::Start(LOADER_STAGE1_BLOCKLIST))?;
+    let mut blocklist = Vec::new();
+    for _ in 0..LOADER_STAGE1_BLOCKLIST_ENTRIES {
+        let start = f.read_u32::<LittleEndian>()?;
+        let count = f.read_u32::<LittleEndian>()?;
+        if count == 0 {
+            break;
+        }
+        blocklist.push((start, count));
+    }
+    Ok(blocklist)
+}
+
+/// work out the logical sector size of an existing image
+///
+/// a GPT header or FAT boot sector only turns up in the right place
+/// for the right sector size. failing both, assume the default.
+pub fn detect_sector_size<R: Read + Seek>(f: &mut R) -> anyhow::Result<u16> {
+    for &size in SECTOR_SIZES {
+        let mut signature = [0; 8];
+        f.seek(SeekFrom::Start(size as u64))?;
+        if f.read_exact(&mut signature).is_ok() && &signature == crate::gpt::SIGNATURE {
+            return Ok(size);
+        }
+    }
+
+    let mut mbr = [0; 512];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_exact(&mut mbr)
+        .context("could not read boot sector")?;
+
+    for entry in mbr[446..510].chunks(16) {
+        let start = (&entry[8..12]).read_u32::<LittleEndian>()? as u64;
+        if entry[4] == 0 || start == 0 {
+            continue;
+        }
+        for &size in SECTOR_SIZES {
+            let mut boot = [0; 512];
+            f.seek(SeekFrom::Start(start * size as u64))?;
+            if f.read_exact(&mut boot).is_err() {
+                continue;
+            }
+            let bytes_per_sector = (&boot[11..13]).read_u16::<LittleEndian>()?;
+            if boot[510..512] == [0x55, 0xaa] && bytes_per_sector == size {
+                return Ok(size);
+            }
+        }
+    }
+
+    Ok(DEFAULT_SECTOR_SIZE)
+}
+
+/// the boot code stage1 replaced, kept so it can be put back
+#[derive(Clone)]
+pub struct Backup {
+    pub boot_code: [u8; BOOT_CODE_SIZE],
+    /// CRC32 of the boot code area as installed, stage1 and blocklist
+    /// both, to tell if something else has been installed since
+    pub installed_crc: u32,
+}
+
+/// where the backup goes: the first sector after where a GPT's entries
+/// end, so it is in the gap before the first partition on MBR and GPT
+/// disks alike
+pub fn backup_lba(sector_size: u16) -> u64 {
+    blue_layout::backup_lba(sector_size)
+}
+
+static_assertions::const_assert_eq!(
+    blue_layout::backup_lba(512),
+    crate::gpt::Gpt::first_usable(512)
+);
+static_assertions::const_assert_eq!(
+    blue_layout::backup_lba(4096),
+    crate::gpt::Gpt::first_usable(4096)
+);
+
+/// is this boot code one of our own stage1s, with any blocklist?
+pub fn is_stage1(boot_code: &[u8]) -> bool {
+    let len = LOADER_STAGE1.len().min(LOADER_STAGE1_SECTOR_SIZE as usize);
+    boot_code.starts_with(&LOADER_STAGE1[..len])
+}
+
+/// read the boot code area, the first 440 bytes of the disk
+pub fn read_boot_code<R: Read + Seek>(f: &mut R) -> anyhow::Result<[u8; BOOT_CODE_SIZE]> {
+    let mut boot_code = [0; BOOT_CODE_SIZE];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_exact(&mut boot_code)
+        .context("could not read boot code")?;
+    Ok(boot_code)
+}
+
+/// read the backup sector, if there is a good one
+///
+/// the sector holds the magic, a CRC32 of the saved boot code, the
+/// CRC32 of what was installed over it, and then the boot code itself
+pub fn read_backup<R: Read + Seek>(f: &mut R, sector_size: u16) -> anyhow::Result<Option<Backup>> {
+    let mut sector = [0; 16 + BOOT_CODE_SIZE];
+    f.seek(SeekFrom::Start(
+        backup_lba(sector_size) * sector_size as u64,
+    ))?;
+    if f.read_exact(&mut sector).is_err() || &sector[..8] != BACKUP_MAGIC {
+        return Ok(None);
+    }
+
+    let crc = (&sector[8..12]).read_u32::<LittleEndian>()?;
+    let installed_crc = (&sector[12..16]).read_u32::<LittleEndian>()?;
+    let mut boot_code = [0; BOOT_CODE_SIZE];
+    boot_code.copy_from_slice(&sector[16..]);
+    if crc32fast::hash(&boot_code) != crc {
+        anyhow::bail!("boot code backup is corrupt");
+    }
+
+    Ok(Some(Backup {
+        boot_code,
+        installed_crc,
+    }))
+}
+
+/// write the backup sector
+pub fn write_backup<W: Write + Seek>(
+    f: &mut W,
+    sector_size: u16,
+    backup: &Backup,
+) -> anyhow::Result<()> {
+    let mut sector = vec![0; sector_size as usize];
+    sector[..8].copy_from_slice(BACKUP_MAGIC);
+    (&mut sector[8..12]).write_u32::<LittleEndian>(crc32fast::hash(&backup.boot_code))?;
+    (&mut sector[12..16]).write_u32::<LittleEndian>(backup.installed_crc)?;
+    sector[16..16 + BOOT_CODE_SIZE].copy_from_slice(&backup.boot_code);
+
+    f.seek(SeekFrom::Start(
+        backup_lba(sector_size) * sector_size as u64,
+    ))?;
+    f.write_all(&sector)
+        .context("could not write boot code backup")?;
+    Ok(())
+}
+
+/// zero the backup sector, once the boot code is back in place
+pub fn clear_backup<W: Write + Seek>(f: &mut W, sector_size: u16) -> anyhow::Result<()> {
+    f.seek(SeekFrom::Start(
+        backup_lba(sector_size) * sector_size as u64,
+    ))?;
+    f.write_all(&vec![0; sector_size as usize])
+        .context("could not clear boot code backup")?;
+    Ok(())
+}
@@ -1,101 +1,180 @@
-use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
 
-use byteorder::{LittleEndian, WriteBytesExt};
-use rand::Rng;
+use anyhow::Context;
+use clap::Parser;
 
-// this should be the same as in loader-stage1/linker.ld
-const LOADER_STAGE1_BLOCKLIST: u64 = 360;
+use blue_tool::image::{self, ImageBuilder};
+use blue_tool::{format, inspect, install, manifest, verify};
 
-const LOADER_STAGE1: &[u8] = include_bytes!(env!("BLUE_LOADER_STAGE1"));
-
-// stage1 must fit before the partition table
-static_assertions::const_assert!(LOADER_STAGE1.len() <= 440);
-
-const LOADER_STAGE2: &[u8] = include_bytes!(env!("BLUE_LOADER_STAGE2"));
-const LOADER_STAGE3: &[u8] = include_bytes!(env!("BLUE_LOADER_STAGE3"));
-
-const SECTOR_SIZE: u16 = 512;
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut rng = rand::thread_rng();
+/// Build and inspect disk images for the blue loader.
+#[derive(Parser, Debug)]
+#[clap(version, about)]
+struct Opts {
+    #[clap(subcommand)]
+    command: Command,
+}
 
-    let f = std::fs::File::options()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open("disk.img")?;
-    f.set_len(SECTOR_SIZE as u64 * 2 * 1024 * 16)?;
-    let mut f = fscommon::BufStream::new(f);
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Build a new disk image with the loader installed
+    Build {
+        /// Where to write the image
+        #[clap(short, long, default_value = "disk.img")]
+        output: PathBuf,
+        /// Describe the image with a TOML manifest
+        #[clap(short, long)]
+        manifest: Option<PathBuf>,
+        /// Size of the image, in bytes or with a K, M, or G suffix [default: 16M]
+        #[clap(short, long, parse(try_from_str = manifest::parse_size))]
+        size: Option<u64>,
+        /// Partition table to write [default: mbr]
+        #[clap(short, long, arg_enum)]
+        table: Option<manifest::PartitionScheme>,
+        /// Container format to write the image in
+        #[clap(long, arg_enum, default_value = "raw")]
+        format: format::Format,
+        /// Filesystem for the loader partition [default: fat32]
+        #[clap(short, long, arg_enum)]
+        filesystem: Option<manifest::Filesystem>,
+        /// Logical sector size of the image, 512 or 4096 [default: 512]
+        #[clap(long)]
+        sector_size: Option<u16>,
+        /// Put stage2 in the gap before the first partition, not in the filesystem
+        #[clap(long)]
+        embed_stage2: bool,
+        /// Copy a host file or directory into the loader partition, as SOURCE[:DEST]
+        #[clap(short, long, multiple_occurrences = true)]
+        add: Vec<String>,
+        /// Build reproducibly, deriving disk signatures, GUIDs, and volume IDs from
+        /// this seed. Also enabled by SOURCE_DATE_EPOCH, which sets the file timestamps
+        #[clap(long)]
+        seed: Option<u64>,
+    },
+    /// Install the loader into an existing disk image
+    Install {
+        /// The image to install into
+        image: PathBuf,
+        /// Partition number (1-4) to install into, instead of the first FAT partition
+        #[clap(short, long)]
+        partition: Option<usize>,
+        /// Put stage2 in the gap before the first partition, not in the filesystem
+        #[clap(long)]
+        embed_stage2: bool,
+    },
+    /// Replace the loader in an image with this version, keeping everything else
+    Upgrade {
+        /// The image to upgrade
+        image: PathBuf,
+        /// Partition number holding the loader, instead of the first FAT partition
+        #[clap(short, long)]
+        partition: Option<usize>,
+    },
+    /// Put back the boot code the loader replaced when it was installed
+    Uninstall {
+        /// The image to uninstall from
+        image: PathBuf,
+        /// Restore even if the boot code has changed since the install
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print the partitions, filesystems, and loader blocklist of a disk image
+    Inspect {
+        /// The image to inspect
+        image: PathBuf,
+        /// Print the report as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check that the loader in a disk image matches its blocklist
+    Verify {
+        /// The image to verify
+        image: PathBuf,
+        /// Partition number holding the loader, instead of the first FAT partition
+        #[clap(short, long)]
+        partition: Option<usize>,
+    },
+}
 
-    let mut mbr = mbrman::MBR::new_from(&mut f, SECTOR_SIZE as u32, rng.gen())?;
-    mbr[1] = mbrman::MBRPartitionEntry {
-        boot: false,
-        sys: 0x0c, // FAT32 with LBA
-        first_chs: mbrman::CHS::empty(),
-        last_chs: mbrman::CHS::empty(),
-        starting_lba: 2048,
-        sectors: mbr.disk_size - 2048,
+/// decide whether a build should be reproducible, from --seed and
+/// SOURCE_DATE_EPOCH (see https://reproducible-builds.org/specs/source-date-epoch/)
+fn reproducible(seed: Option<u64>) -> anyhow::Result<Option<image::Reproducible>> {
+    let epoch = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => Some(
+            value
+                .trim()
+                .parse::<i64>()
+                .with_context(|| format!("invalid SOURCE_DATE_EPOCH: {:?}", value))?,
+        ),
+        Err(std::env::VarError::NotPresent) => None,
+        Err(e) => return Err(e).context("invalid SOURCE_DATE_EPOCH"),
     };
-    mbr.write_into(&mut f)?;
-
-    f.seek(SeekFrom::Start(0))?;
-    f.write_all(LOADER_STAGE1)?;
-
-    let fs_start = mbr[1].starting_lba as u64 * SECTOR_SIZE as u64;
-    let mut blocklist: Vec<(u64, u32)> = Vec::new();
-
-    {
-        let mut fatimg = fscommon::StreamSlice::new(
-            &mut f,
-            fs_start,
-            fs_start + mbr[1].sectors as u64 * SECTOR_SIZE as u64,
-        )?;
-
-        fatfs::format_volume(
-            &mut fatfs::StdIoWrapper::new(&mut fatimg),
-            fatfs::FormatVolumeOptions::new()
-                .fat_type(fatfs::FatType::Fat32)
-                .volume_id(rng.gen())
-                .volume_label(*b"Blue\0\0\0\0\0\0\0"),
-        )?;
 
-        let fs = fatfs::FileSystem::new(fatimg, fatfs::FsOptions::new())?;
-        let root = fs.root_dir();
-        let mut stage2 = root.create_file("blue-loader-stage2.bin")?;
-        stage2.write_all(LOADER_STAGE2)?;
-        let mut stage3 = root.create_file("blue-loader-stage3.bin")?;
-        stage3.write_all(LOADER_STAGE3)?;
-        let mut hello = root.create_file("hello.txt")?;
-        hello.write_all(b"Hello, blue!")?;
+    Ok(match (seed, epoch) {
+        (None, None) => None,
+        (seed, epoch) => Some(image::Reproducible {
+            seed: seed.or_else(|| epoch.map(|e| e as u64)).unwrap_or(0),
+            // FAT can't go earlier than 1980, so 0 becomes that
+            timestamp: epoch.unwrap_or(0),
+        }),
+    })
+}
 
-        for extent in stage2.extents() {
-            let extent = extent?;
-            let start = fs_start + extent.offset;
-            let size = extent.size;
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+    match opts.command {
+        Command::Build {
+            output,
+            manifest,
+            size,
+            table,
+            format,
+            filesystem,
+            sector_size,
+            embed_stage2,
+            add,
+            seed,
+        } => {
+            let mut manifest = match manifest {
+                Some(path) => manifest::Manifest::load(&path)?,
+                None => manifest::Manifest::builtin(16 << 20, Default::default()),
+            };
+            if let Some(size) = size {
+                manifest.size = size;
+            }
+            if let Some(table) = table {
+                manifest.table = table;
+            }
+            if let Some(sector_size) = sector_size {
+                manifest.sector_size = sector_size;
+            }
+            if embed_stage2 {
+                manifest.embed_stage2 = true;
+            }
+            let loader = manifest.loader_partition();
+            if let Some(filesystem) = filesystem {
+                manifest.partitions[loader].filesystem = filesystem;
+            }
+            for spec in add {
+                manifest.partitions[loader]
+                    .files
+                    .push(manifest::FileEntry::from_spec(&spec));
+            }
 
-            if let Some(last) = blocklist.last_mut() {
-                let last_end = last.0 + last.1 as u64;
-                if start == last_end {
-                    last.1 += size;
-                } else {
-                    blocklist.push((start, size));
-                }
-            } else {
-                blocklist.push((start, size));
+            let mut builder = ImageBuilder::from_manifest(manifest).format(format);
+            if let Some(reproducible) = reproducible(seed)? {
+                builder = builder.reproducible(reproducible);
             }
+            builder.write_to(&output)?;
+            Ok(())
         }
+        Command::Install {
+            image,
+            partition,
+            embed_stage2,
+        } => install::install(&image, partition, embed_stage2),
+        Command::Upgrade { image, partition } => install::upgrade(&image, partition),
+        Command::Uninstall { image, force } => install::uninstall(&image, force),
+        Command::Inspect { image, json } => inspect::inspect(&image, json),
+        Command::Verify { image, partition } => verify::verify(&image, partition),
     }
-
-    // write blocklist to stage1
-    f.seek(SeekFrom::Start(LOADER_STAGE1_BLOCKLIST))?;
-    for (start, size) in blocklist.iter() {
-        assert!(start % SECTOR_SIZE as u64 == 0);
-        let start_sec = start / SECTOR_SIZE as u64;
-        assert!((start_sec as u32) as u64 == start_sec);
-        let size_sec = (size + SECTOR_SIZE as u32 - 1) / SECTOR_SIZE as u32;
-        f.write_u32::<LittleEndian>(start_sec as u32)?;
-        f.write_u32::<LittleEndian>(size_sec)?;
-    }
-
-    Ok(())
 }
@@ -0,0 +1,444 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// a declarative description of a disk image
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Manifest {
+    /// total image size in bytes
+    #[serde(deserialize_with = "deserialize_size")]
+    pub size: u64,
+    #[serde(default)]
+    pub table: PartitionScheme,
+    /// logical sector size in bytes, 512 or 4096
+    #[serde(default = "default_sector_size")]
+    pub sector_size: u16,
+    /// partitions start on multiples of this many sectors
+    #[serde(default = "default_alignment")]
+    pub alignment: u64,
+    /// put stage2 in the gap before the first partition, instead of
+    /// in the loader partition's filesystem
+    #[serde(default)]
+    pub embed_stage2: bool,
+    #[serde(default, rename = "partition")]
+    pub partitions: Vec<Partition>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Partition {
+    /// size in bytes, or None to fill the rest of the disk
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub filesystem: Filesystem,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub volume_id: Option<u32>,
+    /// mark as active in the MBR
+    #[serde(default)]
+    pub active: bool,
+    /// install the loader into this partition
+    #[serde(default)]
+    pub loader: bool,
+    #[serde(default)]
+    pub files: Vec<FileEntry>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FileEntry {
+    /// path inside the filesystem
+    pub dest: String,
+    /// host file or directory tree to copy from
+    #[serde(default)]
+    pub source: Option<PathBuf>,
+    /// literal file contents, instead of a source
+    #[serde(default)]
+    pub contents: Option<String>,
+}
+
+#[derive(Deserialize, clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionScheme {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Deserialize, clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl Default for PartitionScheme {
+    fn default() -> Self {
+        Self::Mbr
+    }
+}
+
+impl Default for Filesystem {
+    fn default() -> Self {
+        Self::Fat32
+    }
+}
+
+impl Filesystem {
+    /// partition type byte to use in an MBR, for a partition of this
+    /// many bytes that ends before byte end
+    pub fn mbr_type(&self, size: u64, end: u64) -> u8 {
+        // the classic types are only for partitions CHS can reach
+        let chs_limit = 1024 * 255 * 63 * 512;
+        match self {
+            Self::Fat12 => 0x01,
+            Self::Fat16 if size < 32 << 20 && end <= chs_limit => 0x04,
+            Self::Fat16 if end <= chs_limit => 0x06,
+            Self::Fat16 => 0x0e, // FAT16 with LBA
+            Self::Fat32 => 0x0c, // FAT32 with LBA
+        }
+    }
+
+    pub fn fat_type(&self) -> fatfs::FatType {
+        match self {
+            Self::Fat12 => fatfs::FatType::Fat12,
+            Self::Fat16 => fatfs::FatType::Fat16,
+            Self::Fat32 => fatfs::FatType::Fat32,
+        }
+    }
+}
+
+fn default_alignment() -> u64 {
+    2048
+}
+
+fn default_sector_size() -> u16 {
+    crate::loader::DEFAULT_SECTOR_SIZE
+}
+
+/// parse a size in bytes, with an optional K, M, or G suffix
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, scale) = match s.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size: {:?}", s))?;
+    value
+        .checked_mul(scale)
+        .ok_or_else(|| format!("size too large: {:?}", s))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+fn deserialize_size<'de, D>(d: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Size::deserialize(d)? {
+        Size::Bytes(n) => Ok(n),
+        Size::Text(s) => parse_size(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+fn deserialize_opt_size<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_size(d).map(Some)
+}
+
+impl FileEntry {
+    /// parse a SOURCE[:DEST] command line argument
+    ///
+    /// without a DEST, files land in the root under their own name,
+    /// and directories are merged into the root
+    pub fn from_spec(spec: &str) -> Self {
+        let (source, dest) = match spec.split_once(':') {
+            Some((source, dest)) => (source, dest.to_owned()),
+            None => {
+                let path = Path::new(spec);
+                let dest = if path.is_dir() {
+                    String::new()
+                } else {
+                    path.file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                        .unwrap_or_default()
+                };
+                (spec, dest)
+            }
+        };
+        Self {
+            dest,
+            source: Some(PathBuf::from(source)),
+            contents: None,
+        }
+    }
+}
+
+impl Partition {
+    /// a FAT32 partition filling the rest of the disk, holding the loader
+    pub fn loader() -> Self {
+        Self {
+            label: Some("Blue".to_owned()),
+            loader: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl Manifest {
+    /// an empty layout of the given size, with default settings
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            table: PartitionScheme::default(),
+            sector_size: default_sector_size(),
+            alignment: default_alignment(),
+            embed_stage2: false,
+            partitions: Vec::new(),
+        }
+    }
+
+    /// the layout used when no manifest is given
+    pub fn builtin(size: u64, table: PartitionScheme) -> Self {
+        Self {
+            table,
+            partitions: vec![Partition {
+                files: vec![FileEntry {
+                    dest: "hello.txt".to_owned(),
+                    source: None,
+                    contents: Some("Hello, blue!".to_owned()),
+                }],
+                ..Partition::loader()
+            }],
+            ..Self::new(size)
+        }
+    }
+
+    /// load a manifest from a TOML file
+    ///
+    /// source paths are relative to the directory holding the manifest
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        let mut manifest: Self =
+            toml::from_str(&text).with_context(|| format!("could not parse {}", path.display()))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for part in manifest.partitions.iter_mut() {
+            for file in part.files.iter_mut() {
+                if let Some(ref mut source) = file.source {
+                    *source = base.join(&*source);
+                }
+            }
+        }
+
+        manifest
+            .validate()
+            .with_context(|| format!("invalid manifest {}", path.display()))?;
+        Ok(manifest)
+    }
+
+    /// check everything that can be checked without building
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !crate::loader::SECTOR_SIZES.contains(&self.sector_size) {
+            anyhow::bail!(
+                "sector size {} is not one of {:?}",
+                self.sector_size,
+                crate::loader::SECTOR_SIZES
+            );
+        }
+        if self.alignment == 0 {
+            anyhow::bail!("alignment must not be zero");
+        }
+        if self.partitions.is_empty() {
+            anyhow::bail!("no partitions");
+        }
+        if self.table == PartitionScheme::Mbr && self.partitions.len() > 4 {
+            anyhow::bail!("MBR can only hold 4 partitions");
+        }
+        if self.partitions.iter().filter(|p| p.loader).count() > 1 {
+            anyhow::bail!("only one partition can hold the loader");
+        }
+        if let Some((_, rest)) = self.partitions.split_last() {
+            if rest.iter().any(|p| p.size.is_none()) {
+                anyhow::bail!("only the last partition can leave out its size");
+            }
+        }
+
+        for part in self.partitions.iter() {
+            if let Some(ref label) = part.label {
+                if label.len() > 11 || !label.is_ascii() {
+                    anyhow::bail!("volume label {:?} is not up to 11 ASCII characters", label);
+                }
+            }
+            for file in part.files.iter() {
+                if file.source.is_some() == file.contents.is_some() {
+                    anyhow::bail!(
+                        "file {:?} needs exactly one of source or contents",
+                        file.dest
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// index of the partition that gets the loader
+    pub fn loader_partition(&self) -> usize {
+        self.partitions.iter().position(|p| p.loader).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> anyhow::Result<Manifest> {
+        let manifest: Manifest = toml::from_str(text)?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// check that text parses but doesn't validate, with an error
+    /// mentioning what
+    fn rejects(text: &str, what: &str) {
+        let manifest: Manifest = toml::from_str(text).unwrap();
+        let error = manifest.validate().unwrap_err().to_string();
+        assert!(
+            error.contains(what),
+            "{:?} does not mention {:?}",
+            error,
+            what
+        );
+    }
+
+    #[test]
+    fn minimal() {
+        let manifest = parse("size = \"64M\"\n[[partition]]\n").unwrap();
+        assert_eq!(manifest.size, 64 << 20);
+        assert_eq!(manifest.table, PartitionScheme::Mbr);
+        assert_eq!(manifest.sector_size, 512);
+        assert_eq!(manifest.alignment, 2048);
+        assert_eq!(manifest.partitions[0].filesystem, Filesystem::Fat32);
+        assert_eq!(manifest.partitions[0].size, None);
+    }
+
+    #[test]
+    fn sector_size_and_alignment() {
+        for size in [512, 4096] {
+            parse(&format!(
+                "size = 1\nsector-size = {}\n[[partition]]\n",
+                size
+            ))
+            .unwrap();
+        }
+        for size in [0, 256, 1024, 2048, 8192] {
+            let text = format!("size = 1\nsector-size = {}\n[[partition]]\n", size);
+            rejects(&text, "sector size");
+        }
+        rejects("size = 1\nalignment = 0\n[[partition]]\n", "alignment");
+        parse("size = 1\nalignment = 1\n[[partition]]\n").unwrap();
+    }
+
+    #[test]
+    fn partition_count() {
+        rejects("size = 1\n", "no partitions");
+
+        let four = "size = 1\n".to_owned() + &"[[partition]]\nsize = 1\n".repeat(4);
+        parse(&four).unwrap();
+        let five = "size = 1\n".to_owned() + &"[[partition]]\nsize = 1\n".repeat(5);
+        rejects(&five, "4 partitions");
+        parse(&("table = \"gpt\"\n".to_owned() + &five)).unwrap();
+    }
+
+    #[test]
+    fn loader_partition() {
+        let none = parse("size = 1\n[[partition]]\nsize = 1\n[[partition]]\n").unwrap();
+        assert_eq!(none.loader_partition(), 0);
+
+        let second =
+            parse("size = 1\n[[partition]]\nsize = 1\n[[partition]]\nloader = true\n").unwrap();
+        assert_eq!(second.loader_partition(), 1);
+
+        rejects(
+            "size = 1\n[[partition]]\nsize = 1\nloader = true\n[[partition]]\nloader = true\n",
+            "only one partition",
+        );
+    }
+
+    #[test]
+    fn unsized_partition_last() {
+        parse("size = 1\n[[partition]]\nsize = \"1M\"\n[[partition]]\n").unwrap();
+        rejects(
+            "size = 1\n[[partition]]\n[[partition]]\nsize = \"1M\"\n",
+            "last partition",
+        );
+        rejects("size = 1\n[[partition]]\n[[partition]]\n", "last partition");
+    }
+
+    #[test]
+    fn labels() {
+        parse("size = 1\n[[partition]]\nlabel = \"ELEVEN CHAR\"\n").unwrap();
+        rejects(
+            "size = 1\n[[partition]]\nlabel = \"TWELVE CHARS\"\n",
+            "volume label",
+        );
+        rejects(
+            "size = 1\n[[partition]]\nlabel = \"BL\u{fc}\"\n",
+            "volume label",
+        );
+    }
+
+    #[test]
+    fn source_or_contents() {
+        let entry = |fields: &str| {
+            format!(
+                "size = 1\n[[partition]]\n[[partition.files]]\ndest = \"a\"\n{}",
+                fields
+            )
+        };
+        parse(&entry("source = \"a\"\n")).unwrap();
+        parse(&entry("contents = \"a\"\n")).unwrap();
+        rejects(&entry(""), "exactly one");
+        rejects(&entry("source = \"a\"\ncontents = \"a\"\n"), "exactly one");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4 << 10));
+        assert_eq!(parse_size("16M"), Ok(16 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn mbr_types() {
+        let chs_limit = 1024 * 255 * 63 * 512;
+        assert_eq!(Filesystem::Fat12.mbr_type(1 << 20, 2 << 20), 0x01);
+        assert_eq!(Filesystem::Fat16.mbr_type(16 << 20, 32 << 20), 0x04);
+        assert_eq!(Filesystem::Fat16.mbr_type(64 << 20, 128 << 20), 0x06);
+        assert_eq!(Filesystem::Fat16.mbr_type(64 << 20, chs_limit), 0x06);
+        assert_eq!(Filesystem::Fat16.mbr_type(16 << 20, chs_limit + 512), 0x0e);
+        assert_eq!(Filesystem::Fat16.mbr_type(1 << 30, 4 << 30), 0x0e);
+        assert_eq!(Filesystem::Fat32.mbr_type(64 << 20, 128 << 20), 0x0c);
+        assert_eq!(Filesystem::Fat32.mbr_type(1 << 30, 4 << 30), 0x0c);
+    }
+}
@@ -0,0 +1,218 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::install;
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::loader::{self, Image};
+
+/// the loader files as found in the filesystem
+struct Found {
+    stage2: Option<(Vec<u8>, Vec<(u64, u32)>)>,
+    stage3: Option<u64>,
+}
+
+fn read_stages(f: &mut Image, fs_start: u64, fs_end: u64) -> anyhow::Result<Found> {
+    let fs = loader::open_fs(f, fs_start, fs_end)?;
+    let root = fs.root_dir();
+
+    let stage2 = match root.open_file(loader::LOADER_STAGE2_NAME) {
+        Ok(mut file) => {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)
+                .with_context(|| format!("could not read {}", loader::LOADER_STAGE2_NAME))?;
+            let extents = loader::file_extents(&mut file, fs_start)?;
+            Some((data, extents))
+        }
+        Err(_) => None,
+    };
+
+    let stage3 = match root.open_file(loader::LOADER_STAGE3_NAME) {
+        Ok(mut file) => Some(file.seek(SeekFrom::End(0))?),
+        Err(_) => None,
+    };
+
+    Ok(Found { stage2, stage3 })
+}
+
+/// check that the loader in an image is consistent, returning a list
+/// of everything that is wrong with it
+fn check(f: &mut Image, partition: Option<usize>) -> anyhow::Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    let mut boot = [0; 512];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_exact(&mut boot)
+        .context("could not read boot sector")?;
+    if boot[510..512] != [0x55, 0xaa] {
+        problems.push(format!(
+            "boot sector signature is {:02x}{:02x}, not 55aa",
+            boot[510], boot[511]
+        ));
+        return Ok(problems);
+    }
+
+    let body = loader::LOADER_STAGE1_SECTOR_SIZE as usize;
+    if boot[..body] != loader::LOADER_STAGE1[..body] {
+        problems.push("stage1 boot code does not match this blue-tool's stage1".to_owned());
+    }
+
+    let sector_size = loader::detect_sector_size(f)?;
+    let stage1_sector_size = LittleEndian::read_u16(&boot[body..]);
+    if stage1_sector_size != sector_size {
+        problems.push(format!(
+            "stage1 expects {}-byte sectors, but the image has {}-byte sectors",
+            stage1_sector_size, sector_size
+        ));
+    }
+
+    let blocklist = loader::read_blocklist(f)?;
+    if blocklist.is_empty() {
+        problems.push("stage1 blocklist is empty".to_owned());
+    }
+
+    let (fs_start, fs_end) = match install::find_partition(f, partition, sector_size) {
+        Ok(range) => range,
+        Err(e) => {
+            problems.push(format!("could not find the loader partition: {:#}", e));
+            return Ok(problems);
+        }
+    };
+    let found = match read_stages(f, fs_start, fs_end) {
+        Ok(found) => found,
+        Err(e) => {
+            problems.push(format!("could not read the loader partition: {:#}", e));
+            return Ok(problems);
+        }
+    };
+
+    match found.stage3 {
+        None => problems.push(format!("{} is missing", loader::LOADER_STAGE3_NAME)),
+        Some(0) => problems.push(format!("{} is empty", loader::LOADER_STAGE3_NAME)),
+        Some(_) => {}
+    }
+
+    // an embedded stage2 is not in the filesystem, so it can only be
+    // checked against this blue-tool's own
+    let embedded = loader::is_embedded(&blocklist, sector_size);
+    let (stage2, extents) = match found.stage2 {
+        _ if embedded => (loader::LOADER_STAGE2.to_vec(), None),
+        Some((stage2, extents)) => (stage2, Some(extents)),
+        None => {
+            problems.push(format!("{} is missing", loader::LOADER_STAGE2_NAME));
+            return Ok(problems);
+        }
+    };
+
+    // compare the blocklist to where the file actually is
+    match extents.map(|extents| loader::encode_blocklist(&extents, sector_size)) {
+        None => {}
+        Some(Ok(expected)) if expected != blocklist => {
+            for i in 0..expected.len().max(blocklist.len()) {
+                let have = blocklist.get(i);
+                let want = expected.get(i);
+                if have != want {
+                    problems.push(format!(
+                        "blocklist entry {} is {} but {} is at {}",
+                        i,
+                        describe(have),
+                        loader::LOADER_STAGE2_NAME,
+                        describe(want),
+                    ));
+                }
+            }
+        }
+        Some(Ok(_)) => {}
+        Some(Err(e)) => problems.push(format!(
+            "{} cannot be loaded: {}",
+            loader::LOADER_STAGE2_NAME,
+            e
+        )),
+    }
+
+    // compare what stage1 would actually load as it is read, never
+    // reading more than stage2's size, so a corrupt blocklist can't ask
+    // for more memory than that
+    let image_sectors = f.seek(SeekFrom::End(0))? / sector_size as u64;
+    let covered: u64 = blocklist
+        .iter()
+        .map(|&(_, count)| count as u64)
+        .sum::<u64>()
+        * sector_size as u64;
+    for &(start, count) in blocklist.iter() {
+        let end = start as u64 + count as u64;
+        if end > image_sectors {
+            problems.push(format!(
+                "blocklist sectors {}..{} are past the end of the image",
+                start, end
+            ));
+            return Ok(problems);
+        }
+    }
+
+    let mut buf = vec![0; sector_size as usize];
+    let mut compared = 0;
+    let mut differs = None;
+    'entries: for &(start, count) in blocklist.iter() {
+        f.seek(SeekFrom::Start(start as u64 * sector_size as u64))?;
+        for _ in 0..count {
+            if compared >= stage2.len() {
+                break 'entries;
+            }
+            f.read_exact(&mut buf)
+                .context("could not read blocklist sectors")?;
+            let want = &stage2[compared..];
+            let n = want.len().min(buf.len());
+            if let Some(offset) = want[..n].iter().zip(buf.iter()).position(|(a, b)| a != b) {
+                differs = Some(compared + offset);
+                break 'entries;
+            }
+            compared += n;
+        }
+    }
+
+    if covered < stage2.len() as u64 {
+        problems.push(format!(
+            "blocklist covers {} bytes, but {} is {} bytes",
+            covered,
+            loader::LOADER_STAGE2_NAME,
+            stage2.len()
+        ));
+    } else if let Some(offset) = differs {
+        problems.push(format!(
+            "blocklist data differs from {} at byte {}",
+            loader::LOADER_STAGE2_NAME,
+            offset
+        ));
+    }
+
+    Ok(problems)
+}
+
+fn describe(entry: Option<&(u32, u32)>) -> String {
+    match entry {
+        Some((start, count)) => format!("lba {} sectors {}", start, count),
+        None => "nothing".to_owned(),
+    }
+}
+
+/// verify the loader in an image, failing with a diagnosis if it is broken
+pub fn verify(path: &Path, partition: Option<usize>) -> anyhow::Result<()> {
+    let f =
+        std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut f = fscommon::BufStream::new(f);
+
+    let problems =
+        check(&mut f, partition).with_context(|| format!("could not verify {}", path.display()))?;
+    if problems.is_empty() {
+        println!("{}: ok", path.display());
+        return Ok(());
+    }
+
+    for problem in problems.iter() {
+        eprintln!("{}: {}", path.display(), problem);
+    }
+    anyhow::bail!("{} problem(s) found in {}", problems.len(), path.display())
+}
@@ -0,0 +1,45 @@
+// the stage1 blocklist, as worked out from stage2's extents
+
+use blue_tool::loader::{encode_blocklist, BlocklistError, LOADER_STAGE1_BLOCKLIST_ENTRIES};
+
+#[test]
+fn rounds_up_to_whole_sectors() {
+    let blocklist = encode_blocklist(&[(1 << 20, 5000), (2 << 20, 512)], 512).unwrap();
+    assert_eq!(blocklist, vec![(2048, 10), (4096, 1)]);
+}
+
+#[test]
+fn counts_in_large_sectors() {
+    let blocklist = encode_blocklist(&[(8 << 20, 40000)], 4096).unwrap();
+    assert_eq!(blocklist, vec![(2048, 10)]);
+}
+
+#[test]
+fn too_many_extents() {
+    let extents: Vec<(u64, u32)> = (0..=LOADER_STAGE1_BLOCKLIST_ENTRIES as u64)
+        .map(|i| (i * 4096, 512))
+        .collect();
+    match encode_blocklist(&extents, 512) {
+        Err(BlocklistError::TooManyExtents { needed, available }) => {
+            assert_eq!(needed, LOADER_STAGE1_BLOCKLIST_ENTRIES + 1);
+            assert_eq!(available, LOADER_STAGE1_BLOCKLIST_ENTRIES);
+        }
+        other => panic!("expected too many extents, got {:?}", other),
+    }
+}
+
+#[test]
+fn unaligned_extent() {
+    match encode_blocklist(&[(4096 + 256, 512)], 512) {
+        Err(BlocklistError::Unaligned { offset }) => assert_eq!(offset, 4096 + 256),
+        other => panic!("expected unaligned, got {:?}", other),
+    }
+}
+
+#[test]
+fn past_32_bit_sectors() {
+    match encode_blocklist(&[(512 << 32, 512)], 512) {
+        Err(BlocklistError::SectorOutOfRange { sector }) => assert_eq!(sector, 1 << 32),
+        other => panic!("expected out of range, got {:?}", other),
+    }
+}
@@ -0,0 +1,339 @@
+// boot images built by blue-tool in headless QEMU, and watch the
+// loader's debug console output for each stage in turn
+//
+// the loader copies everything it prints to port 0xe9, which QEMU's
+// isa-debugcon device writes to a file. set QEMU to use another
+// emulator binary. without one, the images are still built and
+// verified, just not booted.
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// everything a successful boot prints, in order
+const EXPECTED: &[&str] = &[
+    "BLUEloader/1",
+    "BLUEloader/2",
+    "BLUEloader/3",
+    "hello.txt: \"Hello, blue!\"",
+    "end of stage3",
+];
+
+// printed by blue_real's panic handler
+const PANIC: &str = "PANIC";
+
+/// kills QEMU when the test is done with it, pass or fail
+struct Qemu(Child);
+
+impl Drop for Qemu {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+fn qemu() -> Option<String> {
+    let qemu = std::env::var("QEMU").unwrap_or_else(|_| "qemu-system-x86_64".to_owned());
+    let found = Command::new(&qemu)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_or(false, |s| s.success());
+    if found {
+        Some(qemu)
+    } else {
+        eprintln!("skipping: {} not found", qemu);
+        None
+    }
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("boot");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+/// build an image with blue-tool, passing args through
+fn build(name: &str, args: &[&str]) -> PathBuf {
+    let image = scratch(name);
+    let status = Command::new(env!("CARGO_BIN_EXE_blue-tool"))
+        .arg("build")
+        .arg("--output")
+        .arg(&image)
+        .args(args)
+        .env("SOURCE_DATE_EPOCH", "1700000000")
+        .status()
+        .expect("could not run blue-tool");
+    assert!(status.success(), "blue-tool build {:?} failed", args);
+    image
+}
+
+/// build an image with blue-tool, passing args through, and check it
+/// with verify before anything boots it
+fn build_verified(name: &str, args: &[&str]) -> PathBuf {
+    let image = build(name, args);
+    tool("verify", &image);
+    image
+}
+
+/// run blue-tool's subcommand on image, which must succeed
+fn tool(subcommand: &str, image: &Path) {
+    let status = Command::new(env!("CARGO_BIN_EXE_blue-tool"))
+        .arg(subcommand)
+        .arg(image)
+        .status()
+        .expect("could not run blue-tool");
+    assert!(status.success(), "blue-tool {} failed", subcommand);
+}
+
+/// the name and contents of every file in the root of image's loader
+/// partition
+fn root_files(image: &Path) -> Vec<(String, Vec<u8>)> {
+    use std::io::Read;
+
+    let f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(image)
+        .unwrap();
+    let mut f = fscommon::BufStream::new(f);
+    let (start, end) = blue_tool::install::find_partition(&mut f, None, 512).unwrap();
+    let fs = blue_tool::loader::open_fs(&mut f, start, end).unwrap();
+
+    let mut files = Vec::new();
+    for entry in fs.root_dir().iter() {
+        let entry = entry.unwrap();
+        if entry.is_file() {
+            let mut data = Vec::new();
+            entry.to_file().read_to_end(&mut data).unwrap();
+            files.push((entry.file_name(), data));
+        }
+    }
+    files
+}
+
+/// upgrade image in place, checking that the user's files come
+/// through untouched and nothing is left behind
+fn upgrade(image: &Path) {
+    let user_files = |files: Vec<(String, Vec<u8>)>| -> Vec<_> {
+        files
+            .into_iter()
+            .filter(|(name, _)| !name.starts_with("blue-loader-"))
+            .collect()
+    };
+    let before = user_files(root_files(image));
+    assert!(!before.is_empty(), "no user files to check");
+
+    tool("upgrade", image);
+
+    let after = root_files(image);
+    if let Some((name, _)) = after.iter().find(|(name, _)| name.ends_with(".new")) {
+        panic!("upgrade left {} behind", name);
+    }
+    assert_eq!(before, user_files(after), "upgrade changed user files");
+    tool("verify", image);
+}
+
+/// where each of EXPECTED shows up in log, as far as they go in order
+fn progress(log: &str) -> usize {
+    let mut pos = 0;
+    for (i, expected) in EXPECTED.iter().enumerate() {
+        match log[pos..].find(expected) {
+            Some(found) => pos += found + expected.len(),
+            None => return i,
+        }
+    }
+    EXPECTED.len()
+}
+
+/// boot with the given drive arguments, until everything in EXPECTED
+/// has been printed, a panic, or the timeout
+fn boot(qemu: &str, name: &str, drive: &[&str]) {
+    let log_path = scratch(&format!("{}.log", name));
+    std::fs::write(&log_path, "").unwrap();
+
+    let child = Command::new(qemu)
+        .args(["-display", "none", "-monitor", "none", "-serial", "none"])
+        .args(["-no-reboot", "-m", "64M"])
+        .arg("-debugcon")
+        .arg(format!("file:{}", log_path.display()))
+        .args(["-global", "isa-debugcon.iobase=0xe9"])
+        .args(drive)
+        .stdin(Stdio::null())
+        .spawn()
+        .expect("could not start qemu");
+    let mut qemu = Qemu(child);
+
+    let start = Instant::now();
+    loop {
+        let log = String::from_utf8_lossy(&std::fs::read(&log_path).unwrap()).into_owned();
+        if log.contains(PANIC) {
+            panic!("{} panicked during boot:\n{}", name, log);
+        }
+        if progress(&log) == EXPECTED.len() {
+            break;
+        }
+        if let Some(status) = qemu.0.try_wait().unwrap() {
+            panic!("qemu exited with {} during boot:\n{}", status, log);
+        }
+        if start.elapsed() > TIMEOUT {
+            panic!(
+                "{} timed out waiting for {:?}:\n{}",
+                name,
+                EXPECTED[progress(&log)],
+                log
+            );
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// build and verify a raw image, and boot it as the first hard disk if
+/// there is a QEMU to do it
+fn boot_disk(name: &str, args: &[&str]) {
+    let image = build_verified(name, args);
+    if let Some(qemu) = qemu() {
+        let drive = format!("format=raw,file={}", image.display());
+        boot(&qemu, name, &["-drive", &drive]);
+    }
+}
+
+/// boot_disk, but packed into a container format, which QEMU knows as
+/// qemu_format. blue-tool can only verify raw images, so the same build
+/// goes through verify raw first.
+fn boot_container(name: &str, format: &str, qemu_format: &str, args: &[&str]) {
+    build_verified(&format!("{}.raw", name), args);
+    let mut args = args.to_vec();
+    args.extend(["--format", format]);
+    let image = build(name, &args);
+    if let Some(qemu) = qemu() {
+        let drive = format!("format={},file={}", qemu_format, image.display());
+        boot(&qemu, name, &["-drive", &drive]);
+    }
+}
+
+/// boot a raw image as a disk with 4096 byte logical sectors, which
+/// SeaBIOS passes through from virtio, if there is a QEMU to do it
+fn boot_4k(name: &str, image: &Path) {
+    if let Some(qemu) = qemu() {
+        let drive = format!("if=none,id=disk,format=raw,file={}", image.display());
+        let device = "virtio-blk-pci,drive=disk,bootindex=0,\
+                      logical_block_size=4096,physical_block_size=4096";
+        boot(&qemu, name, &["-drive", &drive, "-device", device]);
+    }
+}
+
+#[test]
+fn mbr_fat32() {
+    boot_disk(
+        "mbr-fat32.img",
+        &["--table", "mbr", "--filesystem", "fat32"],
+    );
+}
+
+#[test]
+fn mbr_fat16() {
+    boot_disk("mbr-fat16.img", &["--filesystem", "fat16"]);
+}
+
+#[test]
+fn gpt_fat32() {
+    boot_disk("gpt-fat32.img", &["--table", "gpt", "--size", "64M"]);
+}
+
+#[test]
+fn mbr_embedded_stage2() {
+    boot_disk("mbr-embedded.img", &["--embed-stage2"]);
+}
+
+#[test]
+fn gpt_embedded_stage2() {
+    boot_disk(
+        "gpt-embedded.img",
+        &["--table", "gpt", "--size", "64M", "--embed-stage2"],
+    );
+}
+
+#[test]
+fn gpt_4k_sectors() {
+    let image = build_verified(
+        "gpt-4k.img",
+        &["--table", "gpt", "--size", "64M", "--sector-size", "4096"],
+    );
+    boot_4k("gpt-4k.img", &image);
+}
+
+// 4096 byte sectors on MBR, where the backup sector and the embedding
+// slots move with the sector size
+#[test]
+fn mbr_4k_sectors_embedded_stage2() {
+    let image = build_verified(
+        "mbr-4k.img",
+        &["--size", "64M", "--sector-size", "4096", "--embed-stage2"],
+    );
+    boot_4k("mbr-4k.img", &image);
+}
+
+#[test]
+fn qcow2() {
+    boot_container("container.qcow2", "qcow2", "qcow2", &[]);
+}
+
+#[test]
+fn vmdk() {
+    boot_container("container.vmdk", "vmdk", "vmdk", &[]);
+}
+
+#[test]
+fn vhd() {
+    boot_container("container.vhd", "vhd", "vpc", &[]);
+}
+
+// drive 0x00, which only takes CHS reads
+#[test]
+fn floppy() {
+    let image = build_verified("floppy.img", &["--size", "2880K", "--filesystem", "fat12"]);
+    if let Some(qemu) = qemu() {
+        let drive = format!("if=floppy,format=raw,file={}", image.display());
+        boot(&qemu, "floppy.img", &["-drive", &drive, "-boot", "a"]);
+    }
+}
+
+#[test]
+fn upgraded() {
+    let image = build("upgraded.img", &[]);
+    upgrade(&image);
+    if let Some(qemu) = qemu() {
+        let drive = format!("format=raw,file={}", image.display());
+        boot(&qemu, "upgraded.img", &["-drive", &drive]);
+    }
+}
+
+// twice, so the new stage2 goes in each embedding slot in turn
+#[test]
+fn upgraded_embedded_stage2() {
+    let image = build("upgraded-embedded.img", &["--embed-stage2"]);
+    upgrade(&image);
+    upgrade(&image);
+    if let Some(qemu) = qemu() {
+        let drive = format!("format=raw,file={}", image.display());
+        boot(&qemu, "upgraded-embedded.img", &["-drive", &drive]);
+    }
+}
+
+#[test]
+fn hybrid_iso_as_disk() {
+    boot_disk("hybrid-disk.iso", &["--format", "iso"]);
+}
+
+#[test]
+fn hybrid_iso_as_cdrom() {
+    let image = build_verified("hybrid-cdrom.iso", &["--format", "iso"]);
+    if let Some(qemu) = qemu() {
+        let drive = format!("media=cdrom,format=raw,file={}", image.display());
+        boot(&qemu, "hybrid-cdrom.iso", &["-drive", &drive, "-boot", "d"]);
+    }
+}
@@ -0,0 +1,223 @@
+// copying host files and manifest contents into a FAT filesystem, and
+// the timestamps they get there
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use blue_tool::files::{copy_entry, fat_datetime, unix_seconds};
+use blue_tool::loader::{self, Clock};
+use blue_tool::manifest::FileEntry;
+
+const SIZE: u64 = 8 << 20;
+const NOW: i64 = 1_700_000_000;
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("files");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+/// an empty directory for host files to copy from
+fn host_dir(name: &str) -> PathBuf {
+    let dir = scratch(name);
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// a freshly formatted FAT16 image
+fn empty_image(name: &str) -> loader::Image {
+    let f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(scratch(name))
+        .unwrap();
+    f.set_len(SIZE).unwrap();
+    let mut image = fscommon::BufStream::new(f);
+    let mut slice = fscommon::StreamSlice::new(&mut image, 0, SIZE).unwrap();
+    fatfs::format_volume(
+        &mut fatfs::StdIoWrapper::new(&mut slice),
+        fatfs::FormatVolumeOptions::new().fat_type(fatfs::FatType::Fat16),
+    )
+    .unwrap();
+    drop(slice);
+    image
+}
+
+fn copy(image: &mut loader::Image, entry: FileEntry, keep_times: bool) -> anyhow::Result<()> {
+    let fs = loader::open_fs_with(image, 0, SIZE, Clock::fixed(NOW)).unwrap();
+    copy_entry(&fs, &entry, keep_times)
+}
+
+fn from_host(source: &Path, dest: &str) -> FileEntry {
+    FileEntry {
+        dest: dest.to_owned(),
+        source: Some(source.to_owned()),
+        contents: None,
+    }
+}
+
+fn read(image: &mut loader::Image, path: &str) -> Vec<u8> {
+    let fs = loader::open_fs(image, 0, SIZE).unwrap();
+    let mut data = Vec::new();
+    fs.root_dir()
+        .open_file(path)
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    data
+}
+
+/// the names in a directory, in the order they are stored
+fn names(image: &mut loader::Image, path: &str) -> Vec<String> {
+    let fs = loader::open_fs(image, 0, SIZE).unwrap();
+    let dir = fs.root_dir().open_dir(path).unwrap();
+    let names = dir
+        .iter()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name != "." && name != "..")
+        .collect();
+    names
+}
+
+fn modified(image: &mut loader::Image, path: &str) -> fatfs::DateTime {
+    let fs = loader::open_fs(image, 0, SIZE).unwrap();
+    let modified = fs
+        .root_dir()
+        .iter()
+        .map(|entry| entry.unwrap())
+        .find(|entry| entry.file_name() == path)
+        .unwrap()
+        .modified();
+    modified
+}
+
+/// FAT keeps seconds in twos, so compare to that
+fn same_time(a: fatfs::DateTime, b: fatfs::DateTime) -> bool {
+    a.date == b.date
+        && (a.time.hour, a.time.min, a.time.sec / 2) == (b.time.hour, b.time.min, b.time.sec / 2)
+}
+
+#[test]
+fn nested_dirs() {
+    let host = host_dir("nested");
+    std::fs::create_dir_all(host.join("a").join("b")).unwrap();
+    std::fs::write(host.join("top.txt"), "top").unwrap();
+    std::fs::write(host.join("a").join("b").join("deep.txt"), "deep").unwrap();
+
+    let mut image = empty_image("nested.img");
+    copy(&mut image, from_host(&host, "under/here"), true).unwrap();
+    assert_eq!(read(&mut image, "under/here/top.txt"), b"top");
+    assert_eq!(read(&mut image, "under/here/a/b/deep.txt"), b"deep");
+
+    let entry = FileEntry {
+        dest: "/made/up/along/the/way.txt".to_owned(),
+        source: None,
+        contents: Some("contents".to_owned()),
+    };
+    copy(&mut image, entry, true).unwrap();
+    assert_eq!(read(&mut image, "made/up/along/the/way.txt"), b"contents");
+}
+
+#[test]
+fn sorted_order() {
+    let host = host_dir("sorted");
+    for name in ["delta", "alpha", "Charlie", "bravo.txt", "echo"] {
+        std::fs::write(host.join(name), name).unwrap();
+    }
+
+    let mut image = empty_image("sorted.img");
+    copy(&mut image, from_host(&host, "dir"), true).unwrap();
+    assert_eq!(
+        names(&mut image, "dir"),
+        ["Charlie", "alpha", "bravo.txt", "delta", "echo"]
+    );
+}
+
+#[test]
+fn case_only_collisions() {
+    let host = host_dir("case");
+    std::fs::write(host.join("Readme.txt"), "one").unwrap();
+    std::fs::write(host.join("README.TXT"), "two").unwrap();
+    if std::fs::read_dir(&host).unwrap().count() < 2 {
+        eprintln!("skipping: the host filesystem ignores case too");
+        return;
+    }
+
+    let mut image = empty_image("case.img");
+    let error = copy(&mut image, from_host(&host, ""), true).unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("only in case"),
+        "{:#}",
+        error
+    );
+
+    // and against what is already there
+    let mut image = empty_image("case-existing.img");
+    let entry = FileEntry {
+        dest: "readme.txt".to_owned(),
+        source: None,
+        contents: Some("zero".to_owned()),
+    };
+    copy(&mut image, entry, true).unwrap();
+    let error = copy(
+        &mut image,
+        from_host(&host.join("Readme.txt"), "Readme.txt"),
+        true,
+    )
+    .unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("only in case"),
+        "{:#}",
+        error
+    );
+    assert_eq!(read(&mut image, "readme.txt"), b"zero");
+}
+
+#[test]
+fn keep_times() {
+    let host = host_dir("times");
+    let source = host.join("file.txt");
+    std::fs::write(&source, "time").unwrap();
+    let host_time = unix_seconds(std::fs::metadata(&source).unwrap().modified().unwrap());
+
+    let mut image = empty_image("times.img");
+    copy(&mut image, from_host(&source, "kept.txt"), true).unwrap();
+    copy(&mut image, from_host(&source, "stamped.txt"), false).unwrap();
+
+    let kept = modified(&mut image, "kept.txt");
+    assert!(same_time(kept, fat_datetime(host_time)), "{:?}", kept);
+    let stamped = modified(&mut image, "stamped.txt");
+    assert!(same_time(stamped, fat_datetime(NOW)), "{:?}", stamped);
+}
+
+fn ymdhms(t: fatfs::DateTime) -> (u16, u16, u16, u16, u16, u16) {
+    (
+        t.date.year,
+        t.date.month,
+        t.date.day,
+        t.time.hour,
+        t.time.min,
+        t.time.sec,
+    )
+}
+
+#[test]
+fn fat_datetime_range() {
+    assert_eq!(ymdhms(fat_datetime(NOW)), (2023, 11, 14, 22, 13, 20));
+    assert_eq!(ymdhms(fat_datetime(951_782_400)), (2000, 2, 29, 0, 0, 0));
+
+    // FAT starts at 1980, and ends with 2107
+    let first = (1980, 1, 1, 0, 0, 0);
+    assert_eq!(ymdhms(fat_datetime(315_532_800)), first);
+    assert_eq!(ymdhms(fat_datetime(0)), first);
+    assert_eq!(ymdhms(fat_datetime(-86400 * 365)), first);
+    assert_eq!(ymdhms(fat_datetime(i64::MIN)), first);
+
+    let last = (2107, 12, 31, 23, 59, 59);
+    assert_eq!(ymdhms(fat_datetime(4_354_819_199)), last);
+    assert_eq!(ymdhms(fat_datetime(4_354_819_200)), last);
+    assert_eq!(ymdhms(fat_datetime(i64::MAX)), last);
+}
@@ -0,0 +1,276 @@
+// converting a raw image into each container format, read back here by
+// hand, and checked by qemu-img when it is installed. set QEMU_IMG to
+// use another binary.
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use blue_tool::format::{convert, Format, Identity};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+const CLUSTER: usize = 64 << 10;
+
+/// a small raw image, mostly zeros, that ends partway through a cluster
+fn sample() -> Vec<u8> {
+    let mut raw = vec![0; 5 * (1 << 20) + 3 * 512];
+    for (i, b) in raw[..1024].iter_mut().enumerate() {
+        *b = i as u8 | 1;
+    }
+    raw[3 << 20] = 0xaa;
+    // in the last 2M VHD block and the last, partial cluster
+    let end = raw.len();
+    raw[end - 1] = 0x55;
+    raw
+}
+
+fn identity() -> Identity {
+    Identity {
+        id: *b"0123456789abcdef",
+        time: 1_700_000_000,
+        name: "sample".to_owned(),
+    }
+}
+
+fn pack(raw: &[u8], format: Format) -> Vec<u8> {
+    let mut out = Cursor::new(Vec::new());
+    convert(&mut Cursor::new(raw), &mut out, format, &identity()).unwrap();
+    out.into_inner()
+}
+
+fn div_ceil(a: usize, b: usize) -> usize {
+    (a + b - 1) / b
+}
+
+/// how many chunk_size pieces of raw hold anything but zeros
+fn nonzero_chunks(raw: &[u8], chunk_size: usize) -> usize {
+    raw.chunks(chunk_size)
+        .filter(|c| c.iter().any(|&b| b != 0))
+        .count()
+}
+
+/// raw's contents, padded with zeros to a multiple of chunk_size
+fn padded(raw: &[u8], chunk_size: usize) -> Vec<u8> {
+    let mut raw = raw.to_vec();
+    raw.resize(div_ceil(raw.len(), chunk_size) * chunk_size, 0);
+    raw
+}
+
+#[test]
+fn raw_is_copied() {
+    let raw = sample();
+    assert_eq!(pack(&raw, Format::Raw), raw);
+}
+
+#[test]
+fn qcow2_reads_back() {
+    let raw = sample();
+    let image = pack(&raw, Format::Qcow2);
+    let copied = 1 << 63;
+    let offset_mask = 0x00ff_ffff_ffff_fe00;
+
+    assert_eq!(&image[0..4], b"QFI\xfb");
+    assert_eq!(BigEndian::read_u32(&image[4..]), 2);
+    assert_eq!(BigEndian::read_u64(&image[8..]), 0, "backing file");
+    assert_eq!(BigEndian::read_u32(&image[20..]), 16, "cluster bits");
+    assert_eq!(BigEndian::read_u64(&image[24..]), raw.len() as u64);
+    assert_eq!(BigEndian::read_u32(&image[32..]), 0, "encryption");
+    assert_eq!(image.len() % CLUSTER, 0);
+    let l1_size = BigEndian::read_u32(&image[36..]) as usize;
+    let l1_offset = BigEndian::read_u64(&image[40..]) as usize;
+    let table_offset = BigEndian::read_u64(&image[48..]) as usize;
+    let table_clusters = BigEndian::read_u32(&image[56..]) as usize;
+    assert_eq!(BigEndian::read_u32(&image[60..]), 0, "snapshots");
+
+    // follow L1 and L2 to every cluster of the disk
+    let mut data = Vec::new();
+    let mut data_clusters = 0;
+    for cluster in 0..div_ceil(raw.len(), CLUSTER) {
+        let l2_index = cluster / (CLUSTER / 8);
+        assert!(l2_index < l1_size);
+        let l1_entry = BigEndian::read_u64(&image[l1_offset + l2_index * 8..]);
+        let mut contents = &[0; CLUSTER][..];
+        if l1_entry != 0 {
+            assert_ne!(l1_entry & copied, 0, "L1 entry without COPIED");
+            let l2 = (l1_entry & offset_mask) as usize;
+            assert_eq!(l2 % CLUSTER, 0);
+            let entry = BigEndian::read_u64(&image[l2 + cluster % (CLUSTER / 8) * 8..]);
+            if entry != 0 {
+                assert_ne!(entry & copied, 0, "L2 entry without COPIED");
+                let offset = (entry & offset_mask) as usize;
+                assert_eq!(offset % CLUSTER, 0);
+                contents = &image[offset..offset + CLUSTER];
+                data_clusters += 1;
+            }
+        }
+        data.extend_from_slice(contents);
+    }
+    assert_eq!(data, padded(&raw, CLUSTER));
+    assert_eq!(data_clusters, nonzero_chunks(&raw, CLUSTER));
+
+    // every cluster in the file is counted once, and nothing past it
+    let mut refcounts = Vec::new();
+    for i in 0..table_clusters * CLUSTER / 8 {
+        let block = BigEndian::read_u64(&image[table_offset + i * 8..]) as usize;
+        if block != 0 {
+            for j in 0..CLUSTER / 2 {
+                refcounts.push(BigEndian::read_u16(&image[block + j * 2..]));
+            }
+        }
+    }
+    let clusters = image.len() / CLUSTER;
+    assert!(refcounts.len() >= clusters);
+    assert!(refcounts[..clusters].iter().all(|&r| r == 1));
+    assert!(refcounts[clusters..].iter().all(|&r| r == 0));
+}
+
+#[test]
+fn vmdk_reads_back() {
+    let raw = sample();
+    let image = pack(&raw, Format::Vmdk);
+    let grain = 128 * 512;
+
+    assert_eq!(&image[0..4], b"KDMV");
+    assert_eq!(LittleEndian::read_u32(&image[4..]), 1);
+    let capacity = LittleEndian::read_u64(&image[12..]);
+    assert_eq!(capacity, raw.len() as u64 / 512);
+    assert_eq!(LittleEndian::read_u64(&image[20..]), 128, "grain size");
+    let descriptor_offset = LittleEndian::read_u64(&image[28..]) as usize;
+    let descriptor_size = LittleEndian::read_u64(&image[36..]) as usize;
+    assert_eq!(LittleEndian::read_u32(&image[44..]), 512, "GTEs per GT");
+    let gd_offset = LittleEndian::read_u64(&image[56..]) as usize;
+    let overhead = LittleEndian::read_u64(&image[64..]) as usize;
+    assert_eq!(&image[73..77], b"\n \r\n");
+    assert_eq!(overhead % 128, 0);
+
+    let descriptor = &image[descriptor_offset * 512..(descriptor_offset + descriptor_size) * 512];
+    let descriptor = String::from_utf8_lossy(descriptor);
+    assert!(descriptor.contains("createType=\"monolithicSparse\""));
+    assert!(descriptor.contains(&format!("RW {} SPARSE \"sample\"", capacity)));
+
+    let mut data = Vec::new();
+    let mut grains = 0;
+    for g in 0..div_ceil(raw.len(), grain) {
+        let gt = LittleEndian::read_u32(&image[gd_offset * 512 + g / 512 * 4..]) as usize;
+        let sector = LittleEndian::read_u32(&image[gt * 512 + g % 512 * 4..]) as usize;
+        if sector == 0 {
+            data.extend_from_slice(&vec![0; grain]);
+        } else {
+            assert!(sector >= overhead);
+            data.extend_from_slice(&image[sector * 512..sector * 512 + grain]);
+            grains += 1;
+        }
+    }
+    assert_eq!(data, padded(&raw, grain));
+    assert_eq!(grains, nonzero_chunks(&raw, grain));
+    assert_eq!(image.len(), overhead * 512 + grains * grain);
+}
+
+fn vhd_checksum(data: &[u8], field: usize) -> u32 {
+    let sum = data
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(field..field + 4).contains(i))
+        .fold(0u32, |sum, (_, &b)| sum.wrapping_add(b as u32));
+    !sum
+}
+
+#[test]
+fn vhd_reads_back() {
+    let raw = sample();
+    let image = pack(&raw, Format::Vhd);
+    let block = 2 << 20;
+
+    let footer = &image[..512];
+    assert_eq!(&image[image.len() - 512..], footer, "footer copy");
+    assert_eq!(&footer[0..8], b"conectix");
+    assert_eq!(BigEndian::read_u64(&footer[16..]), 512, "header offset");
+    assert_eq!(BigEndian::read_u64(&footer[48..]), raw.len() as u64);
+    assert_eq!(BigEndian::read_u32(&footer[60..]), 3, "dynamic disk");
+    assert_eq!(BigEndian::read_u32(&footer[64..]), vhd_checksum(footer, 64));
+    assert_eq!(&footer[68..84], b"0123456789abcdef");
+
+    let header = &image[512..1536];
+    assert_eq!(&header[0..8], b"cxsparse");
+    let table_offset = BigEndian::read_u64(&header[16..]) as usize;
+    let entries = BigEndian::read_u32(&header[28..]) as usize;
+    assert_eq!(BigEndian::read_u32(&header[32..]) as usize, block);
+    assert_eq!(BigEndian::read_u32(&header[36..]), vhd_checksum(header, 36));
+    assert_eq!(entries, div_ceil(raw.len(), block));
+
+    let mut data = Vec::new();
+    let mut blocks = 0;
+    for b in 0..entries {
+        let sector = BigEndian::read_u32(&image[table_offset + b * 4..]);
+        if sector == u32::MAX {
+            data.extend_from_slice(&vec![0; block]);
+        } else {
+            let start = sector as usize * 512;
+            assert!(
+                image[start..start + 512].iter().all(|&b| b == 0xff),
+                "bitmap"
+            );
+            data.extend_from_slice(&image[start + 512..start + 512 + block]);
+            blocks += 1;
+        }
+    }
+    assert_eq!(data, padded(&raw, block));
+    assert_eq!(blocks, nonzero_chunks(&raw, block));
+}
+
+fn qemu_img() -> Option<String> {
+    let qemu_img = std::env::var("QEMU_IMG").unwrap_or_else(|_| "qemu-img".to_owned());
+    let found = Command::new(&qemu_img)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_or(false, |s| s.success());
+    if found {
+        Some(qemu_img)
+    } else {
+        eprintln!("skipping: {} not found", qemu_img);
+        None
+    }
+}
+
+fn scratch(name: &str, data: &[u8]) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("format");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+#[test]
+fn qemu_img_agrees() {
+    let qemu_img = match qemu_img() {
+        Some(qemu_img) => qemu_img,
+        None => return,
+    };
+    let raw = sample();
+    let raw_path = scratch("sample.raw", &raw);
+    for (format, name) in [
+        (Format::Qcow2, "qcow2"),
+        (Format::Vmdk, "vmdk"),
+        (Format::Vhd, "vpc"),
+    ] {
+        let path = scratch(&format!("sample.{}", name), &pack(&raw, format));
+        if format != Format::Vhd {
+            // qemu-img has no check for VHD
+            let status = Command::new(&qemu_img)
+                .args(["check", "-f", name])
+                .arg(&path)
+                .status()
+                .unwrap();
+            assert!(status.success(), "qemu-img check failed on {}", name);
+        }
+        let status = Command::new(&qemu_img)
+            .args(["compare", "-f", "raw", "-F", name])
+            .arg(&raw_path)
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success(), "qemu-img compare failed on {}", name);
+    }
+}
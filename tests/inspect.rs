@@ -0,0 +1,187 @@
+// the JSON report from blue-tool inspect, checked against the bytes of
+// the images it describes
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use byteorder::{ByteOrder, LittleEndian};
+use serde_json::Value;
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("inspect");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+/// build an image with blue-tool, passing args through
+fn build(name: &str, args: &[&str]) -> PathBuf {
+    let image = scratch(name);
+    let status = Command::new(env!("CARGO_BIN_EXE_blue-tool"))
+        .arg("build")
+        .arg("--output")
+        .arg(&image)
+        .args(args)
+        .env("SOURCE_DATE_EPOCH", "1700000000")
+        .status()
+        .expect("could not run blue-tool");
+    assert!(status.success(), "blue-tool build {:?} failed", args);
+    image
+}
+
+fn inspect(image: &Path) -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_blue-tool"))
+        .arg("inspect")
+        .arg("--json")
+        .arg(image)
+        .output()
+        .expect("could not run blue-tool");
+    assert!(output.status.success(), "blue-tool inspect failed");
+    serde_json::from_slice(&output.stdout).expect("inspect --json is not JSON")
+}
+
+fn u64_of(value: &Value) -> u64 {
+    value
+        .as_u64()
+        .unwrap_or_else(|| panic!("{} is not a number", value))
+}
+
+/// the byte offset of a FAT filesystem's data region, from its boot sector
+fn data_start(image: &[u8], fs_start: usize) -> usize {
+    let bpb = &image[fs_start..fs_start + 512];
+    let bytes_per_sector = LittleEndian::read_u16(&bpb[11..]) as usize;
+    let reserved = LittleEndian::read_u16(&bpb[14..]) as usize;
+    let root_entries = LittleEndian::read_u16(&bpb[17..]) as usize;
+    let fat_size = match LittleEndian::read_u16(&bpb[22..]) {
+        0 => LittleEndian::read_u32(&bpb[36..]) as usize,
+        n => n as usize,
+    };
+    let root_sectors = (root_entries * 32 + bytes_per_sector - 1) / bytes_per_sector;
+    fs_start + (reserved + bpb[16] as usize * fat_size + root_sectors) * bytes_per_sector
+}
+
+/// a file's contents, read by following the clusters the report gives
+fn read_clusters(image: &[u8], data: usize, cluster_size: usize, entry: &Value) -> Vec<u8> {
+    let mut contents = Vec::new();
+    for run in entry["clusters"].as_array().unwrap() {
+        let first = u64_of(&run[0]) as usize;
+        let count = u64_of(&run[1]) as usize;
+        assert!(first >= 2, "cluster {} is before the data region", first);
+        let start = data + (first - 2) * cluster_size;
+        contents.extend_from_slice(&image[start..start + count * cluster_size]);
+    }
+    let size = u64_of(&entry["size"]) as usize;
+    assert!(contents.len() >= size, "clusters don't cover the file");
+    assert!(
+        contents.len() < size + cluster_size,
+        "clusters past the file"
+    );
+    contents.truncate(size);
+    contents
+}
+
+fn root_entry<'a>(fs: &'a Value, name: &str) -> &'a Value {
+    fs["root"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["name"] == name)
+        .unwrap_or_else(|| panic!("no {} in the report", name))
+}
+
+/// check the loader partition and blocklist in a report against image
+fn check_loader(path: &Path, report: &Value) {
+    let image = std::fs::read(path).unwrap();
+    let sector_size = u64_of(&report["sector_size"]) as usize;
+    let partitions = report["partitions"].as_array().unwrap();
+    assert_eq!(partitions.len(), 1, "{}", report);
+    let part = &partitions[0];
+    assert_eq!(part["number"], 1);
+    assert_eq!(part["filesystem_error"], Value::Null);
+
+    let first_lba = u64_of(&part["first_lba"]) as usize;
+    let sectors = u64_of(&part["sectors"]) as usize;
+    assert_eq!(first_lba % 2048, 0, "partition not aligned");
+    assert!((first_lba + sectors) * sector_size <= image.len());
+
+    let fs = &part["filesystem"];
+    // fatfs goes by cluster count, so this small FAT32 may read as FAT16
+    assert!(
+        fs["fat_type"].as_str().unwrap().starts_with("Fat"),
+        "{}",
+        fs
+    );
+    let cluster_size = u64_of(&fs["cluster_size"]) as usize;
+    assert!(u64_of(&fs["free_clusters"]) < u64_of(&fs["total_clusters"]));
+    let data = data_start(&image, first_lba * sector_size);
+
+    let hello = root_entry(fs, "hello.txt");
+    assert_eq!(hello["directory"], false);
+    assert_eq!(
+        read_clusters(&image, data, cluster_size, hello),
+        b"Hello, blue!"
+    );
+
+    let stage2 = root_entry(fs, blue_tool::loader::LOADER_STAGE2_NAME);
+    assert_eq!(
+        read_clusters(&image, data, cluster_size, stage2),
+        blue_tool::loader::LOADER_STAGE2
+    );
+
+    // the blocklist stage1 follows leads to the same stage2
+    let mut loaded = Vec::new();
+    for entry in report["blocklist"].as_array().unwrap() {
+        let lba = u64_of(&entry["lba"]) as usize;
+        let count = u64_of(&entry["sectors"]) as usize;
+        assert!(lba >= first_lba && lba + count <= first_lba + sectors);
+        loaded.extend_from_slice(&image[lba * sector_size..(lba + count) * sector_size]);
+    }
+    let stage2 = blue_tool::loader::LOADER_STAGE2;
+    assert!(loaded.len() >= stage2.len(), "blocklist is too short");
+    assert!(
+        loaded.len() < stage2.len() + cluster_size,
+        "blocklist is too long"
+    );
+    assert_eq!(&loaded[..stage2.len()], stage2);
+}
+
+#[test]
+fn mbr_report() {
+    let path = build("mbr.img", &[]);
+    let report = inspect(&path);
+    assert_eq!(report["table"], "mbr");
+    assert_eq!(report["sector_size"], 512);
+    assert_eq!(report["disk_guid"], Value::Null);
+    assert_eq!(report["partitions"][0]["type"], "0x0c");
+    assert_eq!(report["partitions"][0]["active"], false);
+    check_loader(&path, &report);
+}
+
+#[test]
+fn gpt_report() {
+    let path = build("gpt.img", &["--table", "gpt", "--size", "64M"]);
+    let report = inspect(&path);
+    assert_eq!(report["table"], "gpt");
+    assert!(report["disk_guid"].is_string());
+    assert_eq!(
+        report["partitions"][0]["type"],
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"
+    );
+    assert_eq!(report["partitions"][0]["first_chs"], Value::Null);
+    check_loader(&path, &report);
+}
+
+#[test]
+fn other_partition_types_are_not_read() {
+    // relabel the loader partition as Linux, over a filesystem that is
+    // still FAT
+    let path = build("linux-type.img", &[]);
+    let mut image = std::fs::read(&path).unwrap();
+    image[446 + 4] = 0x83;
+    std::fs::write(&path, &image).unwrap();
+
+    let report = inspect(&path);
+    let part = &report["partitions"][0];
+    assert_eq!(part["type"], "0x83");
+    assert_eq!(part["filesystem"], Value::Null);
+    assert_eq!(part["filesystem_error"], Value::Null);
+}
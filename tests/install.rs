@@ -0,0 +1,95 @@
+// installing over someone else's boot code, and putting it back with
+// uninstall, on images built by blue-tool
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("install");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+/// run blue-tool with args, returning whether it succeeded
+fn tool(args: &[&str], image: &Path) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_blue-tool"))
+        .args(args)
+        .arg(image)
+        .status()
+        .expect("could not run blue-tool")
+        .success()
+}
+
+fn read_sector0(image: &Path) -> Vec<u8> {
+    let mut sector = vec![0; 512];
+    std::fs::File::open(image)
+        .unwrap()
+        .read_exact(&mut sector)
+        .unwrap();
+    sector
+}
+
+fn write_at(image: &Path, offset: u64, data: &[u8]) {
+    let mut f = std::fs::File::options().write(true).open(image).unwrap();
+    f.seek(SeekFrom::Start(offset)).unwrap();
+    f.write_all(data).unwrap();
+}
+
+/// a fresh image with some other boot loader's code in the MBR,
+/// returning its path and the original first sector
+fn foreign_image(name: &str) -> (PathBuf, Vec<u8>) {
+    let image = scratch(name);
+    let status = Command::new(env!("CARGO_BIN_EXE_blue-tool"))
+        .arg("build")
+        .arg("--output")
+        .arg(&image)
+        .env("SOURCE_DATE_EPOCH", "1700000000")
+        .status()
+        .expect("could not run blue-tool");
+    assert!(status.success(), "blue-tool build failed");
+
+    let boot_code: Vec<u8> = (0..440).map(|i| (i % 251 + 1) as u8).collect();
+    write_at(&image, 0, &boot_code);
+    let original = read_sector0(&image);
+    (image, original)
+}
+
+#[test]
+fn uninstall_restores_boot_code() {
+    let (image, original) = foreign_image("restore.img");
+    assert!(tool(&["install"], &image), "install failed");
+    assert_ne!(read_sector0(&image), original, "install left the MBR alone");
+
+    assert!(tool(&["uninstall"], &image), "uninstall failed");
+    assert_eq!(read_sector0(&image), original);
+}
+
+#[test]
+fn uninstall_refuses_changed_boot_code() {
+    let (image, original) = foreign_image("changed.img");
+    assert!(tool(&["install"], &image), "install failed");
+
+    // as if something else had been installed since
+    write_at(&image, 100, &[0xcc; 8]);
+    assert!(
+        !tool(&["uninstall"], &image),
+        "uninstall restored over changed boot code"
+    );
+
+    assert!(
+        tool(&["uninstall", "--force"], &image),
+        "uninstall --force failed"
+    );
+    assert_eq!(read_sector0(&image), original);
+}
+
+#[test]
+fn reinstall_keeps_first_backup() {
+    let (image, original) = foreign_image("reinstall.img");
+    assert!(tool(&["install"], &image), "install failed");
+    assert!(tool(&["install"], &image), "second install failed");
+
+    assert!(tool(&["uninstall"], &image), "uninstall failed");
+    assert_eq!(read_sector0(&image), original);
+}
@@ -0,0 +1,243 @@
+// hybrid ISO9660 images built by blue-tool, read back by hand: the
+// volume descriptors, both directory trees, and the El Torito boot
+// catalog and image
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+const SECTOR: usize = 2048;
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("iso");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+/// a small hybrid image, with the usual hello.txt and a few host files
+/// whose names don't fit in 8.3
+fn build(name: &str) -> Vec<u8> {
+    let files = scratch(&format!("{}-files", name));
+    std::fs::create_dir_all(files.join("Some Dir")).unwrap();
+    std::fs::write(files.join("A long name.text"), "long").unwrap();
+    std::fs::write(files.join("Some Dir").join("nested.txt"), "nested").unwrap();
+
+    let image = scratch(name);
+    let status = Command::new(env!("CARGO_BIN_EXE_blue-tool"))
+        .arg("build")
+        .arg("--output")
+        .arg(&image)
+        .args(["--format", "iso", "--add"])
+        .arg(&files)
+        .env("SOURCE_DATE_EPOCH", "1700000000")
+        .status()
+        .expect("could not run blue-tool");
+    assert!(status.success(), "blue-tool build failed");
+    std::fs::read(&image).unwrap()
+}
+
+fn sector(image: &[u8], lba: usize) -> &[u8] {
+    &image[lba * SECTOR..(lba + 1) * SECTOR]
+}
+
+/// read a both-endian field, checking the halves agree
+fn both_u16(buf: &[u8]) -> u16 {
+    let value = LittleEndian::read_u16(buf);
+    assert_eq!(BigEndian::read_u16(&buf[2..]), value, "both-endian halves");
+    value
+}
+
+fn both_u32(buf: &[u8]) -> u32 {
+    let value = LittleEndian::read_u32(buf);
+    assert_eq!(BigEndian::read_u32(&buf[4..]), value, "both-endian halves");
+    value
+}
+
+fn ucs2(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(|c| c.to_be_bytes()).collect()
+}
+
+/// a directory record, as far as these tests care
+#[derive(Debug)]
+struct Record {
+    name: Vec<u8>,
+    lba: usize,
+    size: usize,
+    dir: bool,
+}
+
+impl Record {
+    fn parse(buf: &[u8]) -> Self {
+        let name_len = buf[32] as usize;
+        Record {
+            name: buf[33..33 + name_len].to_vec(),
+            lba: both_u32(&buf[2..10]) as usize,
+            size: both_u32(&buf[10..18]) as usize,
+            dir: buf[25] & 2 != 0,
+        }
+    }
+
+    /// the entries of this directory, without . and ..
+    fn entries(&self, image: &[u8]) -> Vec<Record> {
+        assert!(self.dir, "{:?} is not a directory", self.name);
+        let data = &image[self.lba * SECTOR..self.lba * SECTOR + self.size];
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = data[pos] as usize;
+            if len == 0 {
+                // records never cross sectors, so skip the padding
+                pos = (pos / SECTOR + 1) * SECTOR;
+                continue;
+            }
+            assert!(pos % SECTOR + len <= SECTOR, "record crosses a sector");
+            records.push(Record::parse(&data[pos..pos + len]));
+            pos += len;
+        }
+        assert_eq!(records[0].name, [0], "first record is not .");
+        assert_eq!(records[0].lba, self.lba);
+        assert_eq!(records[1].name, [1], "second record is not ..");
+        records.split_off(2)
+    }
+
+    fn find(&self, image: &[u8], name: &[u8]) -> Record {
+        self.entries(image)
+            .into_iter()
+            .find(|r| r.name == name)
+            .unwrap_or_else(|| panic!("no {:?}", String::from_utf8_lossy(name)))
+    }
+
+    fn contents<'a>(&self, image: &'a [u8]) -> &'a [u8] {
+        &image[self.lba * SECTOR..self.lba * SECTOR + self.size]
+    }
+}
+
+/// check a primary or supplementary volume descriptor, returning its
+/// root directory
+fn volume(image: &[u8], lba: usize, kind: u8, volume_id: &[u8]) -> Record {
+    let d = sector(image, lba);
+    assert_eq!(d[0], kind);
+    assert_eq!(&d[1..6], b"CD001");
+    assert_eq!(d[6], 1, "version");
+    assert_eq!(&d[40..40 + volume_id.len()], volume_id);
+    assert_eq!(both_u32(&d[80..88]) as usize * SECTOR, image.len());
+    assert_eq!(both_u16(&d[120..124]), 1, "volume set size");
+    assert_eq!(both_u16(&d[128..132]) as usize, SECTOR);
+    assert_eq!(d[881], 1, "file structure version");
+
+    // the path tables start with the root, which is its own parent
+    let root = Record::parse(&d[156..190]);
+    let l_table = &image[LittleEndian::read_u32(&d[140..144]) as usize * SECTOR..];
+    let m_table = &image[BigEndian::read_u32(&d[148..152]) as usize * SECTOR..];
+    assert_eq!(l_table[0], 1);
+    assert_eq!(LittleEndian::read_u32(&l_table[2..]) as usize, root.lba);
+    assert_eq!(LittleEndian::read_u16(&l_table[6..]), 1);
+    assert_eq!(BigEndian::read_u32(&m_table[2..]) as usize, root.lba);
+    assert_eq!(BigEndian::read_u16(&m_table[6..]), 1);
+    root
+}
+
+#[test]
+fn descriptors() {
+    let image = build("descriptors.iso");
+    assert_eq!(image.len() % SECTOR, 0);
+    assert_eq!(&image[510..512], b"\x55\xaa", "no MBR left");
+
+    volume(&image, 16, 1, b"BLUE ");
+    let boot_record = sector(&image, 17);
+    assert_eq!(boot_record[0], 0);
+    assert_eq!(&boot_record[1..6], b"CD001");
+    assert_eq!(&boot_record[7..30], b"EL TORITO SPECIFICATION");
+    assert_eq!(LittleEndian::read_u32(&boot_record[71..75]), 20);
+    let joliet = sector(&image, 18);
+    assert_eq!(&joliet[88..91], b"%/E", "not Joliet level 3");
+    volume(&image, 18, 2, &ucs2("Blue "));
+    let terminator = sector(&image, 19);
+    assert_eq!(terminator[0], 255);
+    assert_eq!(&terminator[1..6], b"CD001");
+}
+
+#[test]
+fn primary_names() {
+    let image = build("primary.iso");
+    let root = volume(&image, 16, 1, b"BLUE ");
+
+    let names: Vec<Vec<u8>> = root.entries(&image).into_iter().map(|r| r.name).collect();
+    let mut sorted = names.clone();
+    sorted.sort();
+    assert_eq!(names, sorted, "root directory is not sorted");
+    for name in names.iter() {
+        let valid = name
+            .iter()
+            .all(|c| matches!(c, b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' | b';'));
+        assert!(valid, "{:?} is not level 1", String::from_utf8_lossy(name));
+        assert!(name.len() <= 8 + 1 + 3 + 2);
+    }
+
+    let hello = root.find(&image, b"HELLO.TXT;1");
+    assert!(!hello.dir);
+    assert_eq!(hello.contents(&image), b"Hello, blue!");
+    let dir = names
+        .iter()
+        .find(|n| n.starts_with(b"SOME"))
+        .expect("no directory for Some Dir");
+    assert!(!dir.contains(&b'.'), "directory with an extension");
+    let nested = root.find(&image, dir).find(&image, b"NESTED.TXT;1");
+    assert_eq!(nested.contents(&image), b"nested");
+}
+
+#[test]
+fn joliet_names() {
+    let image = build("joliet.iso");
+    let root = volume(&image, 18, 2, &ucs2("Blue "));
+
+    let names: Vec<Vec<u8>> = root.entries(&image).into_iter().map(|r| r.name).collect();
+    let mut sorted = names.clone();
+    sorted.sort();
+    assert_eq!(names, sorted, "root directory is not sorted");
+
+    let hello = root.find(&image, &ucs2("hello.txt"));
+    assert_eq!(hello.contents(&image), b"Hello, blue!");
+    let long = root.find(&image, &ucs2("A long name.text"));
+    assert_eq!(long.contents(&image), b"long");
+    let dir = root.find(&image, &ucs2("Some Dir"));
+    assert!(dir.dir);
+    let nested = dir.find(&image, &ucs2("nested.txt"));
+    assert_eq!(nested.contents(&image), b"nested");
+
+    // both volumes share the file data
+    let primary = volume(&image, 16, 1, b"BLUE ");
+    assert_eq!(primary.find(&image, b"HELLO.TXT;1").lba, hello.lba);
+}
+
+#[test]
+fn boot_catalog() {
+    let image = build("catalog.iso");
+    let catalog = sector(&image, 20);
+
+    assert_eq!(catalog[0], 1, "header id");
+    assert_eq!(catalog[1], 0, "x86 platform");
+    assert_eq!(&catalog[30..32], b"\x55\xaa");
+    let sum = catalog[..32]
+        .chunks(2)
+        .fold(0u16, |sum, w| sum.wrapping_add(LittleEndian::read_u16(w)));
+    assert_eq!(sum, 0, "validation entry checksum");
+
+    assert_eq!(catalog[32], 0x88, "not bootable");
+    assert_eq!(catalog[33], 0, "not no emulation");
+    assert_eq!(LittleEndian::read_u16(&catalog[34..36]), 0, "load segment");
+    let count = LittleEndian::read_u16(&catalog[38..40]) as usize;
+    let lba = LittleEndian::read_u32(&catalog[40..44]) as usize;
+
+    // stage1 with stage2 right where it would load it, all of it
+    // covered by the 512 byte sectors the BIOS is asked for
+    let stage2 = blue_tool::loader::LOADER_STAGE2;
+    let entry = blue_layout::STAGE2_ENTRY as usize;
+    let boot = &image[lba * SECTOR..];
+    assert_eq!(count, (entry + stage2.len() + 511) / 512);
+    assert_eq!(&boot[510..512], b"\x55\xaa", "stage1 signature");
+    assert_eq!(&boot[entry..entry + stage2.len()], stage2);
+    assert!((lba + 1) * SECTOR <= image.len());
+    assert!(lba * SECTOR + count * 512 <= image.len());
+}
@@ -0,0 +1,126 @@
+// copying stage2 into a filesystem whose free space is all in single
+// clusters, so every copy lands in too many pieces and has to be moved
+// aside while the next one is tried
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use blue_tool::loader::{self, BlocklistError, LOADER_STAGE2, LOADER_STAGE2_NAME};
+
+const SIZE: u64 = 8 << 20;
+const CLUSTER: usize = 512;
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("stage2");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+/// clusters a copy of stage2 takes
+fn stage2_clusters() -> usize {
+    let clusters = (LOADER_STAGE2.len() + CLUSTER - 1) / CLUSTER;
+    assert!(
+        clusters > loader::LOADER_STAGE1_BLOCKLIST_ENTRIES,
+        "stage2 fits the blocklist even one cluster at a time"
+    );
+    clusters
+}
+
+/// a FAT16 filesystem whose only free space is holes single clusters
+/// apart
+fn fragmented(name: &str, holes: usize) -> loader::Image {
+    let path = scratch(name);
+    let f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    f.set_len(SIZE).unwrap();
+    let mut image = fscommon::BufStream::new(f);
+    {
+        let mut slice = fscommon::StreamSlice::new(&mut image, 0, SIZE).unwrap();
+        fatfs::format_volume(
+            &mut fatfs::StdIoWrapper::new(&mut slice),
+            fatfs::FormatVolumeOptions::new()
+                .fat_type(fatfs::FatType::Fat16)
+                .bytes_per_cluster(CLUSTER as u32),
+        )
+        .unwrap();
+    }
+
+    {
+        // write two files a cluster at a time, so their clusters
+        // alternate, then fill the rest of the disk
+        let fs = loader::open_fs(&mut image, 0, SIZE).unwrap();
+        let root = fs.root_dir();
+        let mut keep = root.create_file("keep").unwrap();
+        let mut gap = root.create_file("gap").unwrap();
+        for _ in 0..holes {
+            keep.write_all(&[0; CLUSTER]).unwrap();
+            gap.write_all(&[0; CLUSTER]).unwrap();
+        }
+        drop((keep, gap));
+        let free = fs.stats().unwrap().free_clusters() as usize;
+        let mut rest = root.create_file("rest").unwrap();
+        for _ in 0..free {
+            rest.write_all(&[0; CLUSTER]).unwrap();
+        }
+        drop(rest);
+
+        root.remove("gap").unwrap();
+        assert_eq!(fs.stats().unwrap().free_clusters() as usize, holes);
+    }
+    image
+}
+
+/// the names of the stage2 copies copy_stage2 left behind
+fn leftover_holds(image: &mut loader::Image) -> Vec<String> {
+    let fs = loader::open_fs(image, 0, SIZE).unwrap();
+    let names: Vec<String> = fs
+        .root_dir()
+        .iter()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.starts_with("blue-loader-stage2.hold"))
+        .collect();
+    names
+}
+
+#[test]
+fn holds_removed_when_out_of_space() {
+    // room for the first copy, which is moved aside, but not a second
+    let size = stage2_clusters();
+    let mut image = fragmented("out-of-space.img", size + size / 2);
+    {
+        let fs = loader::open_fs(&mut image, 0, SIZE).unwrap();
+        let result = loader::copy_stage2(&fs, 0, 512, LOADER_STAGE2_NAME);
+        assert!(result.is_err(), "stage2 fit in too little space");
+        let message = format!("{:#}", result.unwrap_err());
+        assert!(message.contains("could not write"), "{}", message);
+    }
+    assert_eq!(leftover_holds(&mut image), Vec::<String>::new());
+}
+
+#[test]
+fn holds_removed_when_too_fragmented() {
+    // room for every try, all of them in too many pieces
+    let size = stage2_clusters();
+    let mut image = fragmented("too-fragmented.img", size * 10);
+    {
+        let fs = loader::open_fs(&mut image, 0, SIZE).unwrap();
+        let error = loader::copy_stage2(&fs, 0, 512, LOADER_STAGE2_NAME).unwrap_err();
+        assert!(
+            matches!(
+                error.downcast_ref::<BlocklistError>(),
+                Some(BlocklistError::TooManyExtents { .. })
+            ),
+            "{:#}",
+            error
+        );
+        // only the last try is still taking up space
+        let free = fs.stats().unwrap().free_clusters() as usize;
+        assert_eq!(free, size * 9);
+    }
+    assert_eq!(leftover_holds(&mut image), Vec::<String>::new());
+}